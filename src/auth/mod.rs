@@ -0,0 +1,232 @@
+use std::error::Error;
+use std::process::Command;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+pub(crate) mod oauth2;
+
+/// Supplies the Authorization header added to each request
+pub(crate) enum Auth {
+    /// A fixed header value (basic auth or a static bearer token)
+    Static(Arc<str>),
+    /// A bearer token printed by a command, optionally re-run periodically
+    Command(TokenCache<CommandToken>),
+    /// A bearer token from an OAuth2 client credentials grant, refreshed before it expires
+    OAuth2(TokenCache<oauth2::ClientCredentials>),
+}
+
+impl Auth {
+    /// HTTP basic authentication
+    pub(crate) fn basic(credentials: &str) -> Result<Auth, Box<dyn Error>> {
+        if !credentials.contains(':') {
+            return Err("Basic auth credentials must be of the form user:password".into());
+        }
+        Ok(Auth::Static(
+            format!("Basic {}", STANDARD.encode(credentials)).into(),
+        ))
+    }
+
+    /// A static bearer token
+    pub(crate) fn bearer(token: &str) -> Auth {
+        Auth::Static(format!("Bearer {}", token).into())
+    }
+
+    /// A bearer token printed by a shell command
+    pub(crate) fn command(command: &str, refresh: Option<Duration>) -> Auth {
+        Auth::Command(TokenCache::new(CommandToken {
+            command: command.to_string(),
+            refresh,
+        }))
+    }
+
+    /// A bearer token from an OAuth2 token endpoint
+    pub(crate) fn oauth2(credentials: oauth2::ClientCredentials) -> Auth {
+        Auth::OAuth2(TokenCache::new(credentials))
+    }
+
+    /// Returns the Authorization header value, fetching or refreshing the token first if required. Call this before
+    /// timing the request so token refreshes don't count toward latency
+    pub(crate) fn authorization(&self) -> Result<Arc<str>, Box<dyn Error>> {
+        match self {
+            Auth::Static(value) => Ok(value.clone()),
+            Auth::Command(cache) => cache.get(),
+            Auth::OAuth2(cache) => cache.get(),
+        }
+    }
+}
+
+/// Fetches a new token
+pub(crate) trait TokenSource {
+    fn fetch(&self) -> Result<Token, Box<dyn Error>>;
+}
+
+/// A token and when it should be replaced
+pub(crate) struct Token {
+    pub value: Arc<str>,
+    // None if the token never expires
+    pub expires_in: Option<Duration>,
+}
+
+// A token we've fetched, with the time at which we start trying to refresh it
+struct CachedToken {
+    value: Arc<str>,
+    refresh_at: Option<Instant>,
+    expires_at: Option<Instant>,
+}
+
+/// Shares a token across the workers, refreshing it ahead of expiry. While one worker refreshes, the others keep using
+/// the current token as long as it is still valid
+pub(crate) struct TokenCache<S> {
+    source: S,
+    token: RwLock<Option<CachedToken>>,
+    refresh: Mutex<()>,
+}
+
+impl<S: TokenSource> TokenCache<S> {
+    fn new(source: S) -> TokenCache<S> {
+        TokenCache {
+            source,
+            token: RwLock::new(None),
+            refresh: Mutex::new(()),
+        }
+    }
+
+    fn get(&self) -> Result<Arc<str>, Box<dyn Error>> {
+        let now = Instant::now();
+
+        // Fast path: we have a token that isn't due for a refresh
+        let current = self.token.read().unwrap().as_ref().map(|token| {
+            let refresh = token.refresh_at.is_some_and(|at| now >= at);
+            let valid = token.expires_at.is_none_or(|at| now < at);
+            (token.value.clone(), refresh, valid)
+        });
+        let usable = match current {
+            Some((value, false, _)) => return Ok(value),
+            Some((value, true, true)) => Some(value),
+            _ => None,
+        };
+
+        // If another worker is already refreshing, carry on with the current token while it is still valid
+        let _guard = match (self.refresh.try_lock(), usable) {
+            (Ok(guard), _) => guard,
+            (Err(_), Some(value)) => return Ok(value),
+            (Err(_), None) => self.refresh.lock().unwrap(),
+        };
+
+        // Someone may have refreshed the token while we waited for the lock
+        if let Some(token) = self.token.read().unwrap().as_ref()
+            && token.refresh_at.is_none_or(|at| Instant::now() < at)
+        {
+            return Ok(token.value.clone());
+        }
+
+        debug!("Fetching a new auth token");
+        let fetched_at = Instant::now();
+        let token = self.source.fetch()?;
+        let cached = CachedToken {
+            value: token.value.clone(),
+            refresh_at: token
+                .expires_in
+                .map(|expires_in| fetched_at + expires_in - refresh_margin(expires_in)),
+            expires_at: token.expires_in.map(|expires_in| fetched_at + expires_in),
+        };
+        *self.token.write().unwrap() = Some(cached);
+
+        Ok(token.value)
+    }
+}
+
+// Refresh a little ahead of expiry: 10% of the lifetime, capped at a minute
+fn refresh_margin(expires_in: Duration) -> Duration {
+    (expires_in / 10).min(Duration::from_secs(60))
+}
+
+/// Runs a shell command that prints a bearer token
+pub(crate) struct CommandToken {
+    command: String,
+    refresh: Option<Duration>,
+}
+
+impl TokenSource for CommandToken {
+    fn fetch(&self) -> Result<Token, Box<dyn Error>> {
+        let output = Command::new("sh").arg("-c").arg(&self.command).output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "Token command failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+
+        let token = String::from_utf8(output.stdout)?;
+        let token = token.trim();
+        if token.is_empty() {
+            return Err("Token command printed an empty token".into());
+        }
+
+        Ok(Token {
+            value: format!("Bearer {}", token).into(),
+            expires_in: self.refresh,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    struct CountingSource {
+        fetches: AtomicUsize,
+        expires_in: Option<Duration>,
+    }
+
+    impl TokenSource for CountingSource {
+        fn fetch(&self) -> Result<Token, Box<dyn Error>> {
+            let count = self.fetches.fetch_add(1, Ordering::Relaxed);
+            Ok(Token {
+                value: format!("Bearer {}", count).into(),
+                expires_in: self.expires_in,
+            })
+        }
+    }
+
+    // Verify basic credentials are encoded
+    #[test]
+    fn basic_auth() {
+        let auth = Auth::basic("user:pa:ss").unwrap();
+        assert_eq!("Basic dXNlcjpwYTpzcw==", &*auth.authorization().unwrap());
+        assert!(Auth::basic("nopassword").is_err());
+    }
+
+    // Verify tokens are cached until they are due for a refresh
+    #[test]
+    fn token_refresh() {
+        let cache = TokenCache::new(CountingSource {
+            fetches: AtomicUsize::new(0),
+            expires_in: None,
+        });
+        assert_eq!("Bearer 0", &*cache.get().unwrap());
+        assert_eq!("Bearer 0", &*cache.get().unwrap());
+
+        let cache = TokenCache::new(CountingSource {
+            fetches: AtomicUsize::new(0),
+            expires_in: Some(Duration::ZERO),
+        });
+        assert_eq!("Bearer 0", &*cache.get().unwrap());
+        assert_eq!("Bearer 1", &*cache.get().unwrap());
+    }
+
+    // Verify we can take a token from a command
+    #[test]
+    fn command_token() {
+        let auth = Auth::command("echo ' abc '", None);
+        assert_eq!("Bearer abc", &*auth.authorization().unwrap());
+        assert!(Auth::command("exit 1", None).authorization().is_err());
+    }
+}
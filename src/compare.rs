@@ -0,0 +1,628 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::f64::consts::SQRT_2;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+
+use hdrhistogram::Histogram;
+
+use crate::report::{Percentiles, Report, SCHEMA_VERSION, UrlLatency, decode_histogram};
+use crate::units::format_micros;
+
+/// How far the current results can move from the baseline before they count as a regression
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Tolerances {
+    // Fractional increase in a latency percentile e.g. 0.1 for 10% slower
+    pub latency: f64,
+    // Fractional drop in requests per second
+    pub throughput: f64,
+    // Increase in the error rate, as a fraction of requests
+    pub error_rate: f64,
+    // A change is only a regression if a difference this large would arise by chance with less than this probability
+    pub significance: f64,
+}
+
+impl Default for Tolerances {
+    fn default() -> Self {
+        Tolerances {
+            latency: 0.1,
+            throughput: 0.1,
+            error_rate: 0.01,
+            significance: 0.01,
+        }
+    }
+}
+
+// The percentiles compared, and whether they're checked for regressions. The extreme tail rests on too few requests
+// to judge, so it's only shown
+type PercentileOf = fn(&Percentiles) -> u64;
+const PERCENTILES: [(&str, f64, PercentileOf, bool); 6] = [
+    ("p50", 50.0, |p| p.p50_us, true),
+    ("p75", 75.0, |p| p.p75_us, true),
+    ("p95", 95.0, |p| p.p95_us, true),
+    ("p99", 99.0, |p| p.p99_us, true),
+    ("p99.9", 99.9, |p| p.p99_9_us, false),
+    ("max", 100.0, |p| p.max_us, false),
+];
+
+/// What a change from the baseline amounts to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Verdict {
+    // Within the tolerance
+    Ok,
+    // Beyond the tolerance, but too likely to be down to chance
+    Noise,
+    Improved,
+    Regressed,
+    // Shown but not checked
+    Unchecked,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Verdict::Ok => "ok",
+            Verdict::Noise => "noise",
+            Verdict::Improved => "improved",
+            Verdict::Regressed => "REGRESSED",
+            Verdict::Unchecked => "-",
+        })
+    }
+}
+
+/// One of the results of each run, and how it changed
+#[derive(Debug)]
+pub(crate) struct Delta {
+    pub name: String,
+    pub baseline: String,
+    pub current: String,
+    pub change: String,
+    // Untested when the results don't carry the samples to test
+    pub p_value: Option<f64>,
+    pub verdict: Verdict,
+}
+
+/// How the latency of a URL requested in both runs changed, judged by the median
+#[derive(Debug)]
+pub(crate) struct UrlDelta {
+    pub url: String,
+    pub baseline: Percentiles,
+    pub current: Percentiles,
+    pub change: f64,
+    // Adjusted for the number of URLs tested, so the chance of any of them regressing by chance is the significance
+    pub p_value: f64,
+    pub verdict: Verdict,
+}
+
+/// Whether latency in the current run tends to be higher than the baseline, as the probability that a request from
+/// the current run was slower than one from the baseline (0.5 when neither tends to be slower)
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Shift {
+    pub slower: f64,
+    pub p_value: f64,
+}
+
+/// The current results compared with a baseline
+#[derive(Debug)]
+pub(crate) struct Comparison {
+    pub results: Vec<Delta>,
+    pub shift: Option<Shift>,
+    pub urls: Vec<UrlDelta>,
+    // URLs among the most requested in one run but not the other
+    pub unmatched_urls: usize,
+}
+
+/// Reads a results file written with --output json
+pub(crate) fn load(path: &str) -> Result<Report, Box<dyn Error>> {
+    let report: Report = File::open(path)
+        .map_err(|e| e.to_string())
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).map_err(|e| e.to_string()))
+        .map_err(|e| format!("Unable to read the results in {}: {}", path, e))?;
+    if report.schema_version != SCHEMA_VERSION {
+        return Err(format!(
+            "The results in {} have schema version {}, but this version of hb reads version {}",
+            path, report.schema_version, SCHEMA_VERSION
+        )
+        .into());
+    }
+    Ok(report)
+}
+
+impl Comparison {
+    pub(crate) fn new(
+        baseline: &Report,
+        current: &Report,
+        tolerances: &Tolerances,
+    ) -> Result<Comparison, Box<dyn Error>> {
+        // Results written before the histogram was included can still be compared, but without testing the latency
+        let histograms = match (&baseline.latency_histogram, &current.latency_histogram) {
+            (Some(baseline), Some(current)) => {
+                Some((decode_histogram(baseline)?, decode_histogram(current)?))
+            }
+            _ => None,
+        };
+
+        let mut results = latency_deltas(
+            &baseline.latency,
+            &current.latency,
+            histograms.as_ref(),
+            tolerances,
+        );
+
+        // Throughput is a single figure for the run, so there's nothing to test it with
+        let (before, after) = (
+            baseline.throughput.requests_per_sec,
+            current.throughput.requests_per_sec,
+        );
+        let change = relative(before, after);
+        results.push(Delta {
+            name: "requests/s".to_string(),
+            baseline: format!("{:.1}", before),
+            current: format!("{:.1}", after),
+            change: format!("{:+.1}%", change * 100.0),
+            p_value: None,
+            verdict: verdict(
+                change < -tolerances.throughput,
+                change > tolerances.throughput,
+                None,
+            ),
+        });
+
+        // Errors are counted the same way as the thresholds, so every request without a 1xx, 2xx or 3xx response
+        let (before_errors, before_requests) = baseline.error_counts();
+        let (after_errors, after_requests) = current.error_counts();
+        let (before, after) = (baseline.error_rate(), current.error_rate());
+        let p_value =
+            proportions_test(before_errors, before_requests, after_errors, after_requests);
+        results.push(Delta {
+            name: "error rate".to_string(),
+            baseline: format!("{:.2}%", before * 100.0),
+            current: format!("{:.2}%", after * 100.0),
+            change: format!("{:+.2}pp", (after - before) * 100.0),
+            p_value: Some(p_value),
+            verdict: verdict(
+                after - before > tolerances.error_rate,
+                before - after > tolerances.error_rate,
+                Some(p_value < tolerances.significance),
+            ),
+        });
+
+        let (urls, unmatched_urls) =
+            url_deltas(&baseline.url_latency, &current.url_latency, tolerances)?;
+        Ok(Comparison {
+            results,
+            shift: histograms.and_then(|(baseline, current)| mann_whitney(&baseline, &current)),
+            urls,
+            unmatched_urls,
+        })
+    }
+
+    /// The names of the results that regressed
+    pub(crate) fn regressions(&self) -> Vec<&str> {
+        let results = self
+            .results
+            .iter()
+            .map(|delta| (&delta.name, delta.verdict));
+        let urls = self.urls.iter().map(|delta| (&delta.url, delta.verdict));
+        results
+            .chain(urls)
+            .filter(|(_, verdict)| *verdict == Verdict::Regressed)
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// Outputs the changes from the baseline
+    pub(crate) fn print(&self, baseline: &str) {
+        println!(
+            "\nCompared with {}:\n{:<16}{:<16}{:<16}{:<16}{:<16}result",
+            baseline, "metric", "baseline", "current", "change", "p-value"
+        );
+        for delta in &self.results {
+            println!(
+                "{:<16}{:<16}{:<16}{:<16}{:<16}{}",
+                delta.name,
+                delta.baseline,
+                delta.current,
+                delta.change,
+                delta.p_value.map_or("-".to_string(), format_p_value),
+                delta.verdict
+            );
+        }
+        match self.shift {
+            Some(shift) => println!(
+                "\nA request in the current run was slower than one in the baseline {:.1}% of the time \
+                 (Mann-Whitney p-value {})",
+                shift.slower * 100.0,
+                format_p_value(shift.p_value)
+            ),
+            None => println!(
+                "\nThe latency histogram is missing from one of the results, so latency wasn't tested for significance"
+            ),
+        }
+
+        if self.urls.is_empty() {
+            return;
+        }
+        println!(
+            "\nURLs (by change in median latency, with p-values adjusted for the number of URLs by Holm's method):\n\
+             {:<16}{:<16}{:<16}{:<16}{:<16}{:<16}url",
+            "baseline p50", "current p50", "change", "current p99", "p-value", "result"
+        );
+        for url in &self.urls {
+            println!(
+                "{:<16}{:<16}{:<16}{:<16}{:<16}{:<16}{}",
+                format_micros(url.baseline.p50_us),
+                format_micros(url.current.p50_us),
+                format!("{:+.1}%", url.change * 100.0),
+                format_micros(url.current.p99_us),
+                format_p_value(url.p_value),
+                url.verdict.to_string(),
+                url.url
+            );
+        }
+        if self.unmatched_urls > 0 {
+            println!(
+                "({} URLs among the most requested in only one of the runs aren't compared)",
+                self.unmatched_urls
+            );
+        }
+    }
+}
+
+// Compares each latency percentile, testing whether a different share of the current requests took as long as the
+// baseline's value at that percentile
+fn latency_deltas(
+    baseline: &Percentiles,
+    current: &Percentiles,
+    histograms: Option<&(Histogram<u64>, Histogram<u64>)>,
+    tolerances: &Tolerances,
+) -> Vec<Delta> {
+    PERCENTILES
+        .iter()
+        .map(|&(name, percentile, value, checked)| {
+            let (before, after) = (value(baseline), value(current));
+            let change = relative(before as f64, after as f64);
+            let p_value = histograms
+                .filter(|_| checked)
+                .map(|(baseline, current)| exceedance_test(baseline, current, percentile));
+            let verdict = match checked {
+                true => verdict(
+                    change > tolerances.latency,
+                    change < -tolerances.latency,
+                    p_value.map(|p| p < tolerances.significance),
+                ),
+                false => Verdict::Unchecked,
+            };
+            Delta {
+                name: name.to_string(),
+                baseline: format_micros(before),
+                current: format_micros(after),
+                change: format!("{:+.1}%", change * 100.0),
+                p_value,
+                verdict,
+            }
+        })
+        .collect()
+}
+
+// Compares the latency of the URLs among the most requested in both runs, biggest slowdown first
+fn url_deltas(
+    baseline: &[UrlLatency],
+    current: &[UrlLatency],
+    tolerances: &Tolerances,
+) -> Result<(Vec<UrlDelta>, usize), Box<dyn Error>> {
+    let baseline_urls = baseline
+        .iter()
+        .map(|url| (url.url.as_str(), url))
+        .collect::<HashMap<_, _>>();
+
+    let mut deltas = Vec::new();
+    for after in current {
+        let Some(before) = baseline_urls.get(after.url.as_str()) else {
+            continue;
+        };
+        let shift = mann_whitney(
+            &decode_histogram(&before.histogram)?,
+            &decode_histogram(&after.histogram)?,
+        );
+        let Some(shift) = shift else {
+            continue;
+        };
+        let change = relative(before.latency.p50_us as f64, after.latency.p50_us as f64);
+        deltas.push(UrlDelta {
+            url: after.url.clone(),
+            baseline: before.latency,
+            current: after.latency,
+            change,
+            p_value: shift.p_value,
+            verdict: Verdict::Ok,
+        });
+    }
+
+    // Testing many URLs makes it likely some pass the test by chance, so the p-values are adjusted for the number tested
+    let mut p_values = deltas.iter().map(|delta| delta.p_value).collect::<Vec<_>>();
+    holm(&mut p_values);
+    for (delta, p_value) in deltas.iter_mut().zip(p_values) {
+        delta.p_value = p_value;
+        delta.verdict = verdict(
+            delta.change > tolerances.latency,
+            delta.change < -tolerances.latency,
+            Some(p_value < tolerances.significance),
+        );
+    }
+    deltas.sort_by(|a, b| b.change.total_cmp(&a.change));
+
+    let unmatched = baseline.len() + current.len() - 2 * deltas.len();
+    Ok((deltas, unmatched))
+}
+
+// Adjusts p-values for the number of tests with the Holm-Bonferroni method: the smallest is multiplied by the number of
+// tests, the next by one less and so on, with each kept at least as large as the one before
+fn holm(p_values: &mut [f64]) {
+    let mut order = (0..p_values.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| p_values[*a].total_cmp(&p_values[*b]));
+
+    let tests = p_values.len();
+    let mut largest: f64 = 0.0;
+    for (rank, index) in order.into_iter().enumerate() {
+        largest = largest.max((p_values[index] * (tests - rank) as f64).min(1.0));
+        p_values[index] = largest;
+    }
+}
+
+// Whether a change beyond the tolerance, for better or worse, is a real one. Untested changes are taken at face value
+fn verdict(worse: bool, better: bool, significant: Option<bool>) -> Verdict {
+    match (worse, better, significant) {
+        (false, false, _) => Verdict::Ok,
+        (_, _, Some(false)) => Verdict::Noise,
+        (true, _, _) => Verdict::Regressed,
+        (false, true, _) => Verdict::Improved,
+    }
+}
+
+// The change from before to after as a fraction of before
+fn relative(before: f64, after: f64) -> f64 {
+    match before > 0.0 {
+        true => (after - before) / before,
+        false if after > 0.0 => f64::INFINITY,
+        false => 0.0,
+    }
+}
+
+fn format_p_value(p_value: f64) -> String {
+    match p_value < 0.0001 {
+        true => "<0.0001".to_string(),
+        false => format!("{:.4}", p_value),
+    }
+}
+
+// The Mann-Whitney U test, computed exactly from the histograms with tied values (those in the same bucket) given
+// their average rank
+fn mann_whitney(baseline: &Histogram<u64>, current: &Histogram<u64>) -> Option<Shift> {
+    let (n1, n2) = (baseline.len() as f64, current.len() as f64);
+    if n1 == 0.0 || n2 == 0.0 {
+        return None;
+    }
+
+    let mut counts = BTreeMap::<u64, (u64, u64)>::new();
+    for value in baseline.iter_recorded() {
+        counts.entry(value.value_iterated_to()).or_default().0 += value.count_at_value();
+    }
+    for value in current.iter_recorded() {
+        counts.entry(value.value_iterated_to()).or_default().1 += value.count_at_value();
+    }
+
+    // Sum the ranks of the current requests, and the tie correction to the variance
+    let (mut rank, mut rank_sum, mut ties) = (0.0, 0.0, 0.0);
+    for (before, after) in counts.values() {
+        let tied = (before + after) as f64;
+        rank_sum += *after as f64 * (rank + (tied + 1.0) / 2.0);
+        ties += tied * tied * tied - tied;
+        rank += tied;
+    }
+    let u = rank_sum - n2 * (n2 + 1.0) / 2.0;
+
+    let n = n1 + n2;
+    let variance = n1 * n2 / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)));
+    let z = match variance > 0.0 {
+        true => (u - n1 * n2 / 2.0) / variance.sqrt(),
+        false => 0.0,
+    };
+    Some(Shift {
+        slower: u / (n1 * n2),
+        p_value: p_value(z),
+    })
+}
+
+// Whether a different share of the current requests was at least as slow as the baseline at the percentile. The
+// percentile's whole bucket counts, as it can be the one holding the slowest requests
+fn exceedance_test(baseline: &Histogram<u64>, current: &Histogram<u64>, percentile: f64) -> f64 {
+    let limit = baseline.lowest_equivalent(baseline.value_at_percentile(percentile));
+    let at_least = |latency: &Histogram<u64>| {
+        latency.len() - latency.count_between(0, limit.saturating_sub(1))
+    };
+    proportions_test(
+        at_least(baseline),
+        baseline.len(),
+        at_least(current),
+        current.len(),
+    )
+}
+
+// The two proportion z-test of whether x1 in n1 differs from x2 in n2
+fn proportions_test(x1: u64, n1: u64, x2: u64, n2: u64) -> f64 {
+    if n1 == 0 || n2 == 0 {
+        return 1.0;
+    }
+    let (n1, n2) = (n1 as f64, n2 as f64);
+    let (p1, p2) = (x1 as f64 / n1, x2 as f64 / n2);
+    let pooled = (x1 + x2) as f64 / (n1 + n2);
+    let error = (pooled * (1.0 - pooled) * (1.0 / n1 + 1.0 / n2)).sqrt();
+    match error > 0.0 {
+        true => p_value((p2 - p1) / error),
+        false => 1.0,
+    }
+}
+
+// The two-sided p-value of a standard normal test statistic
+fn p_value(z: f64) -> f64 {
+    erfc(z.abs() / SQRT_2)
+}
+
+// The complementary error function, to within 1.2e-7 (Numerical Recipes' Chebyshev approximation)
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let polynomial = [
+        -1.26551223,
+        1.00002368,
+        0.37409196,
+        0.09678418,
+        -0.18628806,
+        0.27886807,
+        -1.13520398,
+        1.48851587,
+        -0.82215223,
+        0.17087277,
+    ]
+    .iter()
+    .rev()
+    .fold(0.0, |sum, coefficient| coefficient + t * sum);
+    let result = t * (-z * z + polynomial).exp();
+    match x >= 0.0 {
+        true => result,
+        false => 2.0 - result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::encode_histogram;
+    use crate::workers::latency_histogram;
+    use assert_approx_eq::assert_approx_eq;
+
+    fn histogram(values: impl IntoIterator<Item = u64>) -> Histogram<u64> {
+        let mut latency = latency_histogram();
+        for value in values {
+            latency += value;
+        }
+        latency
+    }
+
+    // Verify the Mann-Whitney test matches the textbook figures
+    #[test]
+    fn shift_tested() {
+        let shift = mann_whitney(&histogram(1..=5), &histogram(6..=10)).unwrap();
+        assert_approx_eq!(1.0, shift.slower);
+        assert_approx_eq!(0.0090, shift.p_value, 0.0001);
+
+        let shift = mann_whitney(&histogram(1..=5), &histogram(1..=5)).unwrap();
+        assert_approx_eq!(0.5, shift.slower);
+        assert_approx_eq!(1.0, shift.p_value);
+
+        assert!(mann_whitney(&histogram(1..=5), &histogram([])).is_none());
+        assert_approx_eq!(0.0455, p_value(-2.0), 0.0001);
+    }
+
+    // Verify a difference in proportions is only significant with enough requests behind it
+    #[test]
+    fn proportions_tested() {
+        assert!(proportions_test(10, 1000, 30, 1000) < 0.01);
+        assert!(proportions_test(1, 100, 3, 100) > 0.1);
+        assert_approx_eq!(1.0, proportions_test(0, 100, 0, 100));
+    }
+
+    // Verify percentiles only regress when they're slower beyond the tolerance and the difference isn't noise
+    #[test]
+    fn latency_regressions() {
+        let tolerances = Tolerances::default();
+        let deltas = |before: &Histogram<u64>, after: &Histogram<u64>| {
+            latency_deltas(
+                &Percentiles::new(before),
+                &Percentiles::new(after),
+                Some(&(before.clone(), after.clone())),
+                &tolerances,
+            )
+            .into_iter()
+            .map(|delta| delta.verdict)
+            .collect::<Vec<_>>()
+        };
+        let unchecked = [Verdict::Unchecked, Verdict::Unchecked];
+
+        let baseline = histogram((0..5000).map(|i| 10_000 + i));
+        assert_eq!(
+            [[Verdict::Ok; 4].as_slice(), &unchecked].concat(),
+            deltas(&baseline, &baseline)
+        );
+
+        let slower = histogram((0..5000).map(|i| 12_000 + i));
+        assert_eq!(
+            [[Verdict::Regressed; 4].as_slice(), &unchecked].concat(),
+            deltas(&baseline, &slower)
+        );
+        assert_eq!(
+            [[Verdict::Improved; 4].as_slice(), &unchecked].concat(),
+            deltas(&slower, &baseline)
+        );
+
+        // A handful of requests can't show the same slowdown isn't chance
+        let baseline = histogram((0..5).map(|i| 10_000 + i * 1000));
+        let slower = histogram((0..5).map(|i| 12_000 + i * 1000));
+        assert_eq!(Verdict::Noise, deltas(&baseline, &slower)[0]);
+
+        // Without the histograms the change is taken at face value
+        let untested = latency_deltas(
+            &Percentiles::new(&baseline),
+            &Percentiles::new(&slower),
+            None,
+            &tolerances,
+        );
+        assert_eq!(Verdict::Regressed, untested[0].verdict);
+        assert_eq!(None, untested[0].p_value);
+    }
+
+    // Verify p-values are adjusted for the number of tests, keeping their order
+    #[test]
+    fn holm_adjusted() {
+        let mut p_values = [0.01, 0.04, 0.03, 0.005];
+        holm(&mut p_values);
+        for (expected, actual) in [0.03, 0.06, 0.06, 0.02].iter().zip(p_values) {
+            assert_approx_eq!(expected, actual);
+        }
+
+        let mut p_values = [0.5, 0.9];
+        holm(&mut p_values);
+        assert_eq!([1.0, 1.0], p_values);
+    }
+
+    // Verify URLs are matched between the runs and compared
+    #[test]
+    fn urls_compared() {
+        let url = |url: &str, latency: Histogram<u64>| UrlLatency {
+            url: url.to_string(),
+            latency: Percentiles::new(&latency),
+            histogram: encode_histogram(&latency).unwrap(),
+        };
+        let baseline = [
+            url("/a", histogram(1000..2000)),
+            url("/b", histogram(1000..2000)),
+        ];
+        let current = [
+            url("/b", histogram(1000..2000)),
+            url("/a", histogram(2000..3000)),
+            url("/c", histogram(1000..2000)),
+        ];
+
+        let (deltas, unmatched) = url_deltas(&baseline, &current, &Tolerances::default()).unwrap();
+        assert_eq!(
+            vec![("/a", Verdict::Regressed), ("/b", Verdict::Ok)],
+            deltas
+                .iter()
+                .map(|delta| (delta.url.as_str(), delta.verdict))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, unmatched);
+    }
+}
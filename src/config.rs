@@ -1,13 +1,40 @@
+use std::collections::HashSet;
 use std::error::Error;
 use std::ffi::OsString;
-use std::fs;
-use std::io;
 use std::io::BufRead;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use clap::builder::PossibleValuesParser;
-use clap::{Arg, ArgAction, value_parser};
-use url::Url;
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command, value_parser};
+use regex::Regex;
+use ureq::Agent;
+use url::{Position, Url};
+
+use crate::auth::Auth;
+use crate::auth::oauth2::ClientCredentials;
+use crate::compare::Tolerances;
+use crate::headers;
+use crate::headers::Header;
+use crate::signing::Signer;
+use crate::signing::hmac_header::HmacSigner;
+use crate::signing::sigv4::AwsSigV4;
+use crate::template::Template;
+use crate::threshold::Threshold;
+use crate::urlsource;
+use crate::urlsource::UrlSource;
+use crate::urlsource::filter::UrlFilter;
+use crate::urlsource::indexed::OffsetIndex;
+use crate::urlsource::streaming::StreamingReader;
+use crate::workers::assertions::Assertion;
+use crate::workers::retry::{RetryCondition, RetryPolicy};
+use crate::workers::session::SessionPolicy;
+use crate::workers::setup;
+use crate::workers::setup::SetupStep;
+
+// Overall limit on OAuth2 token requests when the test itself has no timeout
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub(crate) struct Config {
     pub concurrency: u16,
@@ -17,16 +44,60 @@ pub(crate) struct Config {
     pub delay_distrib: DelayDistribution,
     pub slow_percentile: Option<f64>,
     pub http_method: HttpMethod,
-    pub headers: Option<Vec<String>>,
+    pub headers: Vec<Header>,
+    pub auth: Option<Arc<Auth>>,
+    pub signer: Option<Arc<dyn Signer>>,
+    pub session: Option<SessionPolicy>,
+    pub setup: Option<Arc<Vec<SetupStep>>>,
+    pub assertions: Vec<Assertion>,
+    pub timeouts: Timeouts,
+    pub retry: Option<RetryPolicy>,
+    pub discard_body: bool,
+    pub interval: Duration,
+    pub timeline_file: Option<String>,
+    pub hlog_file: Option<String>,
+    pub dashboard: bool,
+    pub output: Option<OutputFormat>,
+    pub output_file: Option<String>,
+    pub thresholds: Vec<Threshold>,
+    pub abort_on_threshold: bool,
+    pub baseline: Option<String>,
+    pub tolerances: Tolerances,
+}
+
+/// Comparing two results files, rather than running a test
+pub(crate) struct CompareConfig {
+    pub baseline: String,
+    pub current: String,
+    pub tolerances: Tolerances,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+/// Limits on how long each request can take, none of which are set by default
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Timeouts {
+    pub connect: Option<Duration>,
+    pub first_byte: Option<Duration>,
+    pub body: Option<Duration>,
+    pub overall: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) enum HttpMethod {
     Get,
     Post,
     Put,
 }
 
+impl HttpMethod {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            HttpMethod::Get => "GET",
+            HttpMethod::Post => "POST",
+            HttpMethod::Put => "PUT",
+        }
+    }
+}
+
 impl FromStr for HttpMethod {
     type Err = ();
 
@@ -52,10 +123,38 @@ pub(crate) enum DelayDistribution {
     NegativeExponential,
 }
 
+impl RequestOrder {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            RequestOrder::Sequential => "sequential",
+            RequestOrder::Random => "random",
+        }
+    }
+}
+
+impl DelayDistribution {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            DelayDistribution::Constant => "constant",
+            DelayDistribution::Uniform => "uniform",
+            DelayDistribution::NegativeExponential => "negative exponential",
+        }
+    }
+}
+
+/// Machine readable formats the results can be written in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OutputFormat {
+    Json,
+    Csv,
+    Markdown,
+    Html,
+}
+
 pub(crate) struct LoadTestContext {
     pub(crate) config: Config,
-    pub(crate) urls: &'static Vec<String>,
-    pub(crate) payloads: &'static Vec<String>,
+    pub(crate) urls: &'static UrlSource,
+    pub(crate) payloads: &'static Vec<Template>,
 }
 
 impl Config {
@@ -118,10 +217,114 @@ impl Config {
                 .short('f')
                 .long("file")
                 .value_name("file")
-                .required_unless_present("urls")
+                .required_unless_present_any(["urls", "sitemap"])
+                .conflicts_with_all(["urls", "sitemap"])
+                .num_args(1)
+                .help("file containing URLs to request, or - for stdin (gzip, zstd and xz compression are detected automatically)"))
+
+            // URLs we test with - extracted from a sitemap (or sitemap index)
+            .arg(Arg::new("sitemap")
+                .long("sitemap")
+                .value_name("file")
                 .conflicts_with("urls")
                 .num_args(1)
-                .help("file containing URLs to request"))
+                .help("sitemap.xml (or sitemap index, optionally gzipped) containing URLs to request. The sitemaps an \
+                          index lists by URL are read from files of the same name next to the index. Combine with -p to rewrite the host of every URL in the sitemap"))
+
+            // Read the URL file lazily rather than loading it into memory
+            .arg(Arg::new("stream")
+                .long("stream")
+                .action(ArgAction::SetTrue)
+                .requires("urlfile")
+                .help("Stream URLs from the file instead of loading them into memory (for files larger than RAM). \
+                          Sequential order reads ahead through a bounded queue, random order indexes line offsets"))
+
+            .arg(Arg::new("prefetch")
+                .value_parser(value_parser!(usize))
+                .long("prefetch")
+                .value_name("urls")
+                .default_value("1024")
+                .requires("stream")
+                .num_args(1)
+                .help("Number of URLs to read ahead when streaming in sequential order"))
+
+            // Filtering, sampling and deduplication of the URLs as they're loaded
+            .arg(Arg::new("include")
+                .value_parser(value_parser!(Regex))
+                .long("include")
+                .value_name("regex")
+                .action(ArgAction::Append)
+                .conflicts_with("stream")
+                .help("Only use URLs matching the pattern (may be repeated)"))
+
+            .arg(Arg::new("exclude")
+                .value_parser(value_parser!(Regex))
+                .long("exclude")
+                .value_name("regex")
+                .action(ArgAction::Append)
+                .conflicts_with("stream")
+                .help("Drop URLs matching the pattern e.g. --exclude '^/health' (may be repeated)"))
+
+            .arg(Arg::new("dedup")
+                .long("dedup")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("stream")
+                .help("Drop duplicate URLs"))
+
+            .arg(Arg::new("sample")
+                .value_parser(value_parser!(f64))
+                .long("sample")
+                .value_name("fraction")
+                .conflicts_with("stream")
+                .num_args(1)
+                .help("Use a random sample of the URLs e.g. 0.1 for 10%"))
+
+            .arg(Arg::new("first")
+                .value_parser(value_parser!(usize))
+                .long("first")
+                .value_name("count")
+                .conflicts_with("stream")
+                .num_args(1)
+                .help("Only use the first N URLs (after filtering)"))
+
+            .arg(Arg::new("skipcomments")
+                .long("skip-comments")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("stream")
+                .help("Skip lines starting with #"))
+
+            .arg(Arg::new("skipblank")
+                .long("skip-blank")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("stream")
+                .help("Skip blank lines"))
+
+            // Results over time
+            .arg(Arg::new("interval")
+                .value_parser(value_parser!(u64).range(1..))
+                .long("interval")
+                .value_name("seconds")
+                .default_value("1")
+                .num_args(1)
+                .help("Length of each interval in the timeline of requests, errors and latency over the run"))
+
+            .arg(Arg::new("timeline")
+                .long("timeline")
+                .value_name("file")
+                .num_args(1)
+                .help("Write the statistics for each interval to a file, as JSON if it ends in .json and CSV otherwise"))
+
+            .arg(Arg::new("hlog")
+                .long("hlog")
+                .value_name("file")
+                .num_args(1)
+                .help("Write the latency histogram for each interval to a file in the HdrHistogram interval log format, \
+                          with histograms tagged by class of response (e.g. 2xx) and phase (e.g. new-connect)"))
+
+            .arg(Arg::new("dashboard")
+                .long("dashboard")
+                .action(ArgAction::SetTrue)
+                .help("Show a live dashboard of the test instead of the progress bar (press q to stop the test early)"))
 
             // Prefix for URLs
             .arg(Arg::new("urlprefix")
@@ -140,6 +343,47 @@ impl Config {
                 .num_args(1)
                 .help("Generate a report of requests over a given latency"))
 
+            // Machine readable results
+            .arg(Arg::new("output")
+                .value_parser(PossibleValuesParser::new(["json", "csv", "markdown", "html"]))
+                .long("output")
+                .value_name("format")
+                .num_args(1)
+                .help("Write the results in a machine readable format (or as a self-contained HTML report with charts), \
+                          to stdout in place of the usual results unless --output-file is given"))
+
+            .arg(Arg::new("outputfile")
+                .long("output-file")
+                .value_name("file")
+                .num_args(1)
+                .requires("output")
+                .help("Write the machine readable results to a file, as well as printing the usual results"))
+
+            // Pass/fail thresholds
+            .arg(Arg::new("threshold")
+                .long("threshold")
+                .value_name("threshold")
+                .action(ArgAction::Append)
+                .help("Fail the run (exit status 2) unless the results meet the threshold e.g. 'p99<300ms', \
+                          'error_rate<0.5%' or 'rps>1000'. Metrics are pNN, max, mean, error_rate, errors and rps \
+                          (may be repeated). Latency thresholds include requests that timed out"))
+
+            .arg(Arg::new("abortonthreshold")
+                .long("abort-on-threshold")
+                .action(ArgAction::SetTrue)
+                .requires("threshold")
+                .help("Stop the test as soon as a threshold can no longer be met, however the rest of the run goes"))
+
+            // Comparison with an earlier run
+            .arg(Arg::new("baseline")
+                .long("baseline")
+                .value_name("file")
+                .num_args(1)
+                .help("Compare the results with those of an earlier run written with --output json, failing the run \
+                          (exit status 2) if they've regressed. See also hb compare"))
+
+            .args(tolerance_args().map(|arg| arg.requires("baseline")))
+
             .arg(Arg::new("httpmethod")
                 .value_parser(PossibleValuesParser::new(["GET", "POST", "PUT"]))
                 .short('m')
@@ -156,29 +400,302 @@ impl Config {
                 .long("header")
                 .value_name("header")
                 .action(ArgAction::Append)
-                .help("Extra header(s) to include in the request e.g. -H 'Content-Type: application/json' -H 'Authorisation: Bearer token'"))
+                .help("Extra header(s) to include in the request e.g. -H 'Content-Type: application/json' -H 'Authorisation: Bearer token'. \
+                          Use -H @file to read headers from a file (one per line). Values may contain the placeholders \
+                          {{worker}}, {{sequence}}, {{random}}, {{uuid}} and {{timestamp}}. Lines in the URL file may also \
+                          carry per-request headers after the URL, separated by tabs"))
+
+            // Authentication
+            .arg(Arg::new("basic")
+                .long("basic")
+                .value_name("user:password")
+                .env("HB_BASIC_AUTH")
+                .hide_env_values(true)
+                .num_args(1)
+                .help("Use HTTP basic authentication"))
+
+            .arg(Arg::new("bearer")
+                .long("bearer")
+                .value_name("token")
+                .env("HB_BEARER_TOKEN")
+                .hide_env_values(true)
+                .num_args(1)
+                .help("Send a static bearer token"))
+
+            .arg(Arg::new("bearercommand")
+                .long("bearer-command")
+                .value_name("command")
+                .num_args(1)
+                .help("Send a bearer token printed by a shell command e.g. --bearer-command 'gcloud auth print-access-token'"))
+
+            .arg(Arg::new("bearerrefresh")
+                .value_parser(value_parser!(u64))
+                .long("bearer-refresh")
+                .value_name("seconds")
+                .requires("bearercommand")
+                .num_args(1)
+                .help("Re-run the bearer token command at this interval (by default it runs once)"))
+
+            .arg(Arg::new("oauth2tokenurl")
+                .long("oauth2-token-url")
+                .value_name("url")
+                .requires_all(["oauth2clientid", "oauth2clientsecret"])
+                .num_args(1)
+                .help("Obtain bearer tokens from this OAuth2 token endpoint using the client credentials grant. \
+                          Tokens are cached and refreshed before they expire"))
+
+            .arg(Arg::new("oauth2clientid")
+                .long("oauth2-client-id")
+                .value_name("id")
+                .requires("oauth2tokenurl")
+                .num_args(1)
+                .help("OAuth2 client id"))
+
+            .arg(Arg::new("oauth2clientsecret")
+                .long("oauth2-client-secret")
+                .value_name("secret")
+                .env("HB_OAUTH2_CLIENT_SECRET")
+                .hide_env_values(true)
+                .requires("oauth2tokenurl")
+                .num_args(1)
+                .help("OAuth2 client secret"))
+
+            .arg(Arg::new("oauth2scope")
+                .long("oauth2-scope")
+                .value_name("scope")
+                .requires("oauth2tokenurl")
+                .num_args(1)
+                .help("OAuth2 scope(s) to request"))
+
+            .group(ArgGroup::new("auth")
+                .args(["basic", "bearer", "bearercommand", "oauth2tokenurl"])
+                .multiple(false))
+
+            // Request signing
+            .arg(Arg::new("hmackey")
+                .long("hmac-key")
+                .value_name("secret")
+                .env("HB_HMAC_KEY")
+                .hide_env_values(true)
+                .conflicts_with_all(["awssigv4", "auth"])
+                .num_args(1)
+                .help("Sign each request with HMAC-SHA256 over the method, path+query, timestamp, signed headers and \
+                          SHA256 of the body (newline separated)"))
+
+            .arg(Arg::new("hmackeyid")
+                .long("hmac-key-id")
+                .value_name("id")
+                .requires("hmackey")
+                .num_args(1)
+                .help("Key id sent alongside the HMAC signature"))
+
+            .arg(Arg::new("hmacsignedheaders")
+                .long("hmac-signed-headers")
+                .value_name("names")
+                .value_delimiter(',')
+                .default_value("host")
+                .requires("hmackey")
+                .help("Comma separated list of headers covered by the HMAC signature"))
+
+            .arg(Arg::new("hmacsignatureheader")
+                .long("hmac-signature-header")
+                .value_name("name")
+                .default_value("X-Signature")
+                .requires("hmackey")
+                .num_args(1)
+                .help("Header carrying the HMAC signature"))
+
+            .arg(Arg::new("hmactimestampheader")
+                .long("hmac-timestamp-header")
+                .value_name("name")
+                .default_value("X-Timestamp")
+                .requires("hmackey")
+                .num_args(1)
+                .help("Header carrying the signature timestamp (seconds since the UNIX epoch)"))
+
+            .arg(Arg::new("hmackeyidheader")
+                .long("hmac-key-id-header")
+                .value_name("name")
+                .default_value("X-Key-Id")
+                .requires("hmackeyid")
+                .num_args(1)
+                .help("Header carrying the key id"))
+
+            .arg(Arg::new("hmacbase64")
+                .long("hmac-base64")
+                .action(ArgAction::SetTrue)
+                .requires("hmackey")
+                .help("Base64 encode the HMAC signature (default is hex)"))
+
+            .arg(Arg::new("awssigv4")
+                .long("aws-sigv4")
+                .value_name("region:service")
+                .conflicts_with("auth")
+                .num_args(1)
+                .help("Sign each request with AWS Signature Version 4 e.g. --aws-sigv4 us-east-1:execute-api. \
+                          Credentials are read from AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN"))
+
+            // Sessions (cookie jar per worker)
+            .arg(Arg::new("cookies")
+                .long("cookies")
+                .action(ArgAction::SetTrue)
+                .help("Give each worker a cookie jar, replaying cookies set by responses on its later requests \
+                          (i.e. each worker models one browser session)"))
+
+            .arg(Arg::new("sessionrequests")
+                .value_parser(value_parser!(u64).range(1..))
+                .long("session-requests")
+                .value_name("requests")
+                .requires("cookies")
+                .num_args(1)
+                .help("Start a new session (empty cookie jar) after this many requests, to simulate new users"))
+
+            .arg(Arg::new("sessionseconds")
+                .value_parser(value_parser!(u64).range(1..))
+                .long("session-seconds")
+                .value_name("seconds")
+                .requires("cookies")
+                .num_args(1)
+                .help("Start a new session (empty cookie jar) after this many seconds"))
+
+            // Setup phase
+            .arg(Arg::new("setup")
+                .long("setup")
+                .value_name("file")
+                .num_args(1)
+                .help("JSON file of requests each worker sends before the load begins (e.g. to log in), capturing values \
+                          from the responses into variables for later requests e.g. \
+                          [{\"method\": \"POST\", \"url\": \"/login\", \"headers\": [\"Content-Type: application/json\"], \
+                          \"body\": \"{\\\"user\\\": \\\"u{{worker}}\\\"}\", \"capture\": {\"token\": \"json:/token\"}}]. \
+                          Captures may be json:/pointer, header:name, cookie:name or regex:pattern, and variables are used \
+                          as {{name}} in headers and payloads. Setup latency is reported separately"))
+
+            // Timeouts
+            .arg(Arg::new("connecttimeout")
+                .value_parser(value_parser!(u64).range(1..))
+                .long("connect-timeout")
+                .value_name("ms")
+                .num_args(1)
+                .help("Give up on connecting (including DNS lookup) after this long"))
+
+            .arg(Arg::new("firstbytetimeout")
+                .value_parser(value_parser!(u64).range(1..))
+                .long("first-byte-timeout")
+                .value_name("ms")
+                .num_args(1)
+                .help("Give up waiting for the response headers after this long"))
+
+            .arg(Arg::new("readtimeout")
+                .value_parser(value_parser!(u64).range(1..))
+                .long("read-timeout")
+                .value_name("ms")
+                .num_args(1)
+                .help("Give up reading the response body after this long"))
+
+            .arg(Arg::new("timeout")
+                .value_parser(value_parser!(u64).range(1..))
+                .long("timeout")
+                .value_name("ms")
+                .num_args(1)
+                .help("Give up on the whole request after this long. Timeouts are reported separately from other errors"))
+
+            .arg(Arg::new("discardbody")
+                .long("discard-body")
+                .action(ArgAction::SetTrue)
+                .help("Discard response bodies as they're read rather than holding each one in memory \
+                          (body assertions and setup captures from the body can't be used)"))
+
+            // Retries (off by default)
+            .arg(Arg::new("retries")
+                .value_parser(value_parser!(u32).range(1..))
+                .long("retries")
+                .value_name("count")
+                .num_args(1)
+                .help("Retry failed requests up to this many times, like a real client would. \
+                          The results cover each request's last attempt, with first attempt and end-to-end latency shown separately"))
+
+            .arg(Arg::new("retryon")
+                .long("retry-on")
+                .value_name("conditions")
+                .default_value("429,502,503,504,connect,reset")
+                .requires("retries")
+                .num_args(1)
+                .help("Status codes, classes (e.g. 5xx) and transport errors (dns, connect, reset, tls, timeout, \
+                          truncated, protocol) to retry"))
+
+            .arg(Arg::new("retrybackoff")
+                .value_parser(value_parser!(u64))
+                .long("retry-backoff")
+                .value_name("ms")
+                .default_value("100")
+                .requires("retries")
+                .num_args(1)
+                .help("Delay before the first retry, doubling for each retry after that (with jitter)"))
+
+            .arg(Arg::new("retrymaxbackoff")
+                .value_parser(value_parser!(u64))
+                .long("retry-max-backoff")
+                .value_name("ms")
+                .default_value("10000")
+                .requires("retries")
+                .num_args(1)
+                .help("Maximum delay between retries"))
+
+            // Response assertions
+            .arg(Arg::new("expectstatus")
+                .long("expect-status")
+                .value_name("codes")
+                .num_args(1)
+                .help("Count responses whose status isn't one of these codes or classes as failures e.g. 200,201,3xx"))
+
+            .arg(Arg::new("expectbody")
+                .long("expect-body")
+                .value_name("text")
+                .action(ArgAction::Append)
+                .help("Count responses whose body doesn't contain the text as failures (may be repeated)"))
+
+            .arg(Arg::new("expectbodyregex")
+                .value_parser(value_parser!(regex::bytes::Regex))
+                .long("expect-body-regex")
+                .value_name("regex")
+                .action(ArgAction::Append)
+                .help("Count responses whose body doesn't match the pattern as failures (may be repeated)"))
+
+            .arg(Arg::new("expectjson")
+                .long("expect-json")
+                .value_name("pointer=value")
+                .action(ArgAction::Append)
+                .help("Count responses where the value at the JSON pointer doesn't equal the expected value as failures \
+                          e.g. --expect-json /status=ok (may be repeated)"))
+
+            .arg(Arg::new("expectheader")
+                .long("expect-header")
+                .value_name("name")
+                .action(ArgAction::Append)
+                .help("Count responses without this header as failures (may be repeated)"))
+
+            .arg(Arg::new("expectsize")
+                .long("expect-size")
+                .value_name("min..max")
+                .num_args(1)
+                .help("Count responses whose body size in bytes is outside these bounds as failures \
+                          e.g. 100..5000, 100.. or ..5000"))
 
             .arg(Arg::new("payloads")
                 .long("payloads")
                 .value_name("payloads")
                 .num_args(1)
-                .help("The payload for POST and PUT requests. Each request in the test takes one line in this file as payload."))
+                .help("The payload for POST and PUT requests. Each request in the test takes one line in this file as payload. \
+                          Use - for stdin (gzip, zstd and xz compression are detected automatically)."))
 
             // Remaining arguments are URLs to test against
             .arg(Arg::new("urls")
                 .index(1)
                 .value_name("URL"))
 
+            .after_help("To compare two results files written with --output json, run: hb compare <baseline> <current>")
             .get_matches_from(args);
 
-        // Extract the URLs
-        let url_prefix = matches.get_one::<String>("urlprefix");
-        let url_file = matches.get_one::<String>("urlfile");
-        let args_urls: Option<Vec<String>> = matches
-            .get_many::<String>("urls")
-            .map(|v| v.into_iter().cloned().collect());
-        let urls = Box::leak(Box::new(load_urls(url_prefix, url_file, args_urls)?));
-
         // Grab basic params
         // TODO cleanup parsing of these arguments
         let concurrency: u16 = *matches.get_one("concurrency").unwrap();
@@ -197,24 +714,246 @@ impl Config {
         };
         let slow_percentile = matches.get_one::<f64>("reportslow").copied();
 
+        let output = matches
+            .get_one::<String>("output")
+            .map(|format| match format.as_str() {
+                "csv" => OutputFormat::Csv,
+                "markdown" => OutputFormat::Markdown,
+                "html" => OutputFormat::Html,
+                _ => OutputFormat::Json,
+            });
+        let output_file = matches.get_one::<String>("outputfile").cloned();
+
+        let thresholds = matches
+            .get_many::<String>("threshold")
+            .unwrap_or_default()
+            .map(|threshold| Threshold::parse(threshold))
+            .collect::<Result<Vec<_>, _>>()?;
+        let abort_on_threshold = matches.get_flag("abortonthreshold");
+        let baseline = matches.get_one::<String>("baseline").cloned();
+        let tolerances = tolerances(&matches);
+
         let http_method = matches.get_one::<String>("httpmethod").unwrap();
         let http_method = HttpMethod::from_str(http_method).expect("Unsupported http method");
 
-        let headers: Option<Vec<String>> = matches
-            .get_many::<String>("headers")
+        // Extract the URLs
+        let url_prefix = matches.get_one::<String>("urlprefix");
+        let url_file = matches.get_one::<String>("urlfile");
+        let sitemap = matches.get_one::<String>("sitemap");
+        let args_urls: Option<Vec<String>> = matches
+            .get_many::<String>("urls")
             .map(|v| v.into_iter().cloned().collect());
+        let urls = if let (true, Some(url_file)) = (matches.get_flag("stream"), url_file) {
+            if http_method != HttpMethod::Get {
+                return Err("Streaming URLs is only supported for GET requests".into());
+            }
+            let url_prefix = url_prefix.map(|p| Url::parse(p)).transpose()?;
+            let prefetch: usize = *matches.get_one("prefetch").unwrap();
+            match order {
+                RequestOrder::Sequential => {
+                    UrlSource::Streaming(StreamingReader::open(url_file, prefetch, url_prefix)?)
+                }
+                RequestOrder::Random => {
+                    UrlSource::Indexed(OffsetIndex::build(url_file, url_prefix)?)
+                }
+            }
+        } else {
+            let sample = matches.get_one::<f64>("sample").copied();
+            if sample.is_some_and(|s| !(0.0..=1.0).contains(&s)) {
+                return Err("The sample fraction must be between 0 and 1".into());
+            }
+            let filter = UrlFilter {
+                skip_comments: matches.get_flag("skipcomments"),
+                skip_blank: matches.get_flag("skipblank"),
+                include: matches
+                    .get_many::<Regex>("include")
+                    .map_or_else(Vec::new, |v| v.cloned().collect()),
+                exclude: matches
+                    .get_many::<Regex>("exclude")
+                    .map_or_else(Vec::new, |v| v.cloned().collect()),
+                dedup: matches.get_flag("dedup"),
+                sample,
+                first: matches.get_one::<usize>("first").copied(),
+            };
+            UrlSource::Memory(load_urls(
+                url_prefix, url_file, sitemap, args_urls, &filter,
+            )?)
+        };
+        let urls = Box::leak(Box::new(urls));
+
+        // Setup steps define the variables that can be used in headers
+        let (setup, variables) = match matches.get_one::<String>("setup") {
+            Some(path) => {
+                let url_prefix = url_prefix.map(|p| Url::parse(p)).transpose()?;
+                let (steps, variables) = setup::load(path, url_prefix.as_ref())?;
+                (Some(Arc::new(steps)), variables)
+            }
+            None => (None, HashSet::new()),
+        };
+
+        let headers = match matches.get_many::<String>("headers") {
+            Some(args) => headers::parse_args(args, &variables)?,
+            None => Vec::new(),
+        };
+
+        let timeout = |name| {
+            matches
+                .get_one::<u64>(name)
+                .map(|ms| Duration::from_millis(*ms))
+        };
+        let timeouts = Timeouts {
+            connect: timeout("connecttimeout"),
+            first_byte: timeout("firstbytetimeout"),
+            body: timeout("readtimeout"),
+            overall: timeout("timeout"),
+        };
+
+        let auth = if let Some(credentials) = matches.get_one::<String>("basic") {
+            Some(Auth::basic(credentials)?)
+        } else if let Some(token) = matches.get_one::<String>("bearer") {
+            Some(Auth::bearer(token))
+        } else if let Some(command) = matches.get_one::<String>("bearercommand") {
+            let refresh = matches
+                .get_one::<u64>("bearerrefresh")
+                .map(|secs| Duration::from_secs(*secs));
+            Some(Auth::command(command, refresh))
+        } else {
+            matches
+                .get_one::<String>("oauth2tokenurl")
+                .map(|token_url| {
+                    Auth::oauth2(ClientCredentials {
+                        token_url: token_url.clone(),
+                        client_id: matches.get_one::<String>("oauth2clientid").unwrap().clone(),
+                        client_secret: matches
+                            .get_one::<String>("oauth2clientsecret")
+                            .unwrap()
+                            .clone(),
+                        scope: matches.get_one::<String>("oauth2scope").cloned(),
+                        agent: token_agent(&timeouts),
+                    })
+                })
+        };
+        let authorization_header = headers
+            .iter()
+            .any(|h| h.name.eq_ignore_ascii_case("Authorization"));
+        if auth.is_some() && authorization_header {
+            return Err(
+                "An Authorization header can't be combined with --basic, --bearer, --bearer-command or OAuth2"
+                    .into(),
+            );
+        }
+        let auth = auth.map(Arc::new);
+
+        let signer: Option<Arc<dyn Signer>> =
+            if let Some(key) = matches.get_one::<String>("hmackey") {
+                Some(Arc::new(HmacSigner {
+                    key: key.as_bytes().to_vec(),
+                    key_id: matches.get_one::<String>("hmackeyid").cloned(),
+                    signature_header: matches
+                        .get_one::<String>("hmacsignatureheader")
+                        .unwrap()
+                        .clone(),
+                    timestamp_header: matches
+                        .get_one::<String>("hmactimestampheader")
+                        .unwrap()
+                        .clone(),
+                    key_id_header: matches
+                        .get_one::<String>("hmackeyidheader")
+                        .unwrap()
+                        .clone(),
+                    signed_headers: matches
+                        .get_many::<String>("hmacsignedheaders")
+                        .unwrap()
+                        .map(|h| h.trim().to_string())
+                        .filter(|h| !h.is_empty())
+                        .collect(),
+                    base64: matches.get_flag("hmacbase64"),
+                }))
+            } else if let Some(scope) = matches.get_one::<String>("awssigv4") {
+                let Some((region, service)) = scope.split_once(':') else {
+                    return Err("--aws-sigv4 must be of the form region:service".into());
+                };
+                Some(Arc::new(AwsSigV4::from_env(region, service)?))
+            } else {
+                None
+            };
+        if signer.is_some() && authorization_header {
+            return Err("An Authorization header can't be combined with request signing".into());
+        }
+
+        let session = matches.get_flag("cookies").then(|| SessionPolicy {
+            reset_requests: matches.get_one::<u64>("sessionrequests").copied(),
+            reset_after: matches
+                .get_one::<u64>("sessionseconds")
+                .map(|secs| Duration::from_secs(*secs)),
+        });
+
+        let retry = match matches.get_one::<u32>("retries") {
+            Some(max_retries) => {
+                let millis = |name| Duration::from_millis(*matches.get_one::<u64>(name).unwrap());
+                Some(RetryPolicy {
+                    max_retries: *max_retries,
+                    retry_on: RetryCondition::parse_list(
+                        matches.get_one::<String>("retryon").unwrap(),
+                    )?,
+                    backoff: millis("retrybackoff"),
+                    max_backoff: millis("retrymaxbackoff"),
+                })
+            }
+            None => None,
+        };
+
+        let mut assertions = Vec::new();
+        if let Some(codes) = matches.get_one::<String>("expectstatus") {
+            assertions.push(Assertion::status(codes)?);
+        }
+        if let Some(texts) = matches.get_many::<String>("expectbody") {
+            assertions.extend(texts.map(|text| Assertion::BodyContains(text.clone())));
+        }
+        if let Some(patterns) = matches.get_many::<regex::bytes::Regex>("expectbodyregex") {
+            assertions.extend(patterns.map(|regex| Assertion::BodyMatches(regex.clone())));
+        }
+        if let Some(expressions) = matches.get_many::<String>("expectjson") {
+            for expression in expressions {
+                assertions.push(Assertion::json(expression)?);
+            }
+        }
+        if let Some(names) = matches.get_many::<String>("expectheader") {
+            assertions.extend(names.map(|name| Assertion::HeaderPresent(name.clone())));
+        }
+        if let Some(bounds) = matches.get_one::<String>("expectsize") {
+            assertions.push(Assertion::body_size(bounds)?);
+        }
+
+        let interval = Duration::from_secs(*matches.get_one::<u64>("interval").unwrap());
+        let timeline_file = matches.get_one::<String>("timeline").cloned();
+        let hlog_file = matches.get_one::<String>("hlog").cloned();
+        let dashboard = matches.get_flag("dashboard");
+
+        let discard_body = matches.get_flag("discardbody");
+        if discard_body && assertions.iter().any(Assertion::needs_body) {
+            return Err("Body assertions can't be used when discarding response bodies".into());
+        }
+        if discard_body
+            && setup
+                .as_ref()
+                .is_some_and(|steps| steps.iter().any(SetupStep::needs_body))
+        {
+            return Err(
+                "Setup steps can't capture from the body when discarding response bodies".into(),
+            );
+        }
 
         let payloads = if let Some(payloads_file) = matches.get_one::<String>("payloads") {
-            info!("Loading payloads from {}", payloads_file);
-            let file = fs::File::open(payloads_file);
-            match file {
-                Ok(file) => io::BufReader::new(file)
-                    .lines()
-                    .map(|l| l.unwrap())
-                    .collect(),
-                // If we are unable to load 'payloads' file simply exit
-                Err(error) => panic!("Unable to open file: {:?}", error),
+            if url_file.is_some_and(|f| f == urlsource::STDIN) && payloads_file == urlsource::STDIN
+            {
+                return Err("Only one of the URLs or payloads can be read from stdin".into());
             }
+            info!("Loading payloads from {}", payloads_file);
+            read_lines(urlsource::open_file(payloads_file)?)?
+                .iter()
+                .map(|payload| Template::parse(payload, &variables))
+                .collect()
         } else {
             vec![]
         };
@@ -227,7 +966,11 @@ impl Config {
                     !payloads.is_empty(),
                     "Payloads must be supplied when http_method is set to POST or PUT"
                 );
-                assert_eq!(urls.len(), 1, "Must only have a single URL for POST or PUT");
+                assert_eq!(
+                    urls.len(),
+                    Some(1),
+                    "Must only have a single URL for POST or PUT"
+                );
             }
             _ => {}
         }
@@ -242,6 +985,24 @@ impl Config {
                 slow_percentile,
                 http_method,
                 headers,
+                auth,
+                signer,
+                session,
+                setup,
+                assertions,
+                timeouts,
+                retry,
+                discard_body,
+                interval,
+                timeline_file,
+                hlog_file,
+                dashboard,
+                output,
+                output_file,
+                thresholds,
+                abort_on_threshold,
+                baseline,
+                tolerances,
             },
             urls,
             payloads,
@@ -249,45 +1010,151 @@ impl Config {
     }
 }
 
+impl CompareConfig {
+    /// Parses the arguments following hb, starting with compare
+    pub(crate) fn from_cmdline<I, T>(args: I) -> Result<CompareConfig, Box<dyn Error>>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Command::new("compare")
+            .bin_name("hb compare")
+            .about("Compare two results files written with --output json, failing (exit status 2) if the current \
+                    results have regressed from the baseline")
+            .arg(Arg::new("baseline")
+                .index(1)
+                .required(true)
+                .value_name("baseline")
+                .help("Results of the earlier run"))
+            .arg(Arg::new("current")
+                .index(2)
+                .required(true)
+                .value_name("current")
+                .help("Results to compare with the baseline"))
+            .args(tolerance_args())
+            .get_matches_from(args);
+
+        Ok(CompareConfig {
+            baseline: matches.get_one::<String>("baseline").unwrap().clone(),
+            current: matches.get_one::<String>("current").unwrap().clone(),
+            tolerances: tolerances(&matches),
+        })
+    }
+}
+
+// How far results can move from the baseline, shared by --baseline and hb compare
+fn tolerance_args() -> [Arg; 4] {
+    [
+        Arg::new("latencytolerance")
+            .value_parser(value_parser!(f64))
+            .long("latency-tolerance")
+            .value_name("percent")
+            .default_value("10")
+            .num_args(1)
+            .help("How much slower a latency percentile (p50 to p99) can be than the baseline"),
+        Arg::new("throughputtolerance")
+            .value_parser(value_parser!(f64))
+            .long("throughput-tolerance")
+            .value_name("percent")
+            .default_value("10")
+            .num_args(1)
+            .help("How much lower the requests per second can be than the baseline"),
+        Arg::new("errorratetolerance")
+            .value_parser(value_parser!(f64))
+            .long("error-rate-tolerance")
+            .value_name("percent")
+            .default_value("1")
+            .num_args(1)
+            .help("How many percentage points higher the error rate can be than the baseline"),
+        Arg::new("significance")
+            .value_parser(value_parser!(f64))
+            .long("significance")
+            .value_name("p-value")
+            .default_value("0.01")
+            .num_args(1)
+            .help("Only count a change beyond the tolerance as a regression if a statistical test puts the chance of \
+                      it being noise below this"),
+    ]
+}
+
+fn tolerances(matches: &ArgMatches) -> Tolerances {
+    let percent = |name| *matches.get_one::<f64>(name).unwrap() / 100.0;
+    Tolerances {
+        latency: percent("latencytolerance"),
+        throughput: percent("throughputtolerance"),
+        error_rate: percent("errorratetolerance"),
+        significance: *matches.get_one::<f64>("significance").unwrap(),
+    }
+}
+
+// Token requests use the test's timeouts, so a hung token endpoint can't stall the workers
+fn token_agent(timeouts: &Timeouts) -> Agent {
+    Agent::config_builder()
+        .timeout_connect(timeouts.connect)
+        .timeout_recv_response(timeouts.first_byte)
+        .timeout_recv_body(timeouts.body)
+        .timeout_global(timeouts.overall.or(Some(TOKEN_TIMEOUT)))
+        .build()
+        .into()
+}
+
 fn load_urls(
     url_prefix: Option<&String>,
     url_file: Option<&String>,
+    sitemap: Option<&String>,
     args_urls: Option<Vec<String>>,
+    filter: &UrlFilter,
 ) -> Result<Vec<String>, Box<dyn Error>> {
-    // Read from a file, or just collect the URLs on the command line
-    let mut urls: Vec<String> = if let Some(url_file) = url_file {
+    // Read from a file or sitemap, or just collect the URLs on the command line
+    let urls: Vec<String> = if let Some(sitemap) = sitemap {
+        let urls = urlsource::sitemap::load(sitemap)?;
+        info!("Loaded {} URLs from sitemap {}", urls.len(), sitemap);
+
+        // Sitemap URLs are always absolute, so strip them back to the path+query so the prefix rewrites the host
+        if url_prefix.is_some() {
+            urls.into_iter().map(|url| strip_origin(&url)).collect()
+        } else {
+            urls
+        }
+    } else if let Some(url_file) = url_file {
         info!("Loading URLs from {}", url_file);
-        // TODO better error handling
-        let file = fs::File::open(url_file).unwrap();
-        io::BufReader::new(file)
-            .lines()
-            .map(|l| l.unwrap())
-            .collect()
+        read_lines(urlsource::open_file(url_file)?)?
     } else {
         args_urls.unwrap().iter().map(|s| (*s).to_owned()).collect()
     };
 
-    // Prefix as required
-    if let Some(url_prefix) = url_prefix {
-        info!("Applying prefixes");
-        let base = Url::parse(url_prefix)?;
-        for url in urls.iter_mut() {
-            match Url::parse(url) {
-                // Nothing required in the OK case
-                Ok(_) => {}
-                // If no base, then fix
-                Err(url::ParseError::RelativeUrlWithoutBase) => match base.join(url) {
-                    Ok(prefixed) => *url = prefixed.into(),
-                    Err(e) => warn!("URL {} is invalid: {}", url, e),
-                },
-                Err(e) => warn!("URL {} is invalid: {}", url, e),
-            }
-        }
+    // Filter, prefix and validate as required
+    let url_prefix = url_prefix.map(|p| Url::parse(p)).transpose()?;
+    let (urls, summary) = filter.apply(urls, url_prefix.as_ref());
+    summary.log(urls.len());
+    if urls.is_empty() {
+        return Err("No valid URLs to request".into());
+    }
+
+    // Check any per-request headers up front, rather than as each request is sent
+    for line in &urls {
+        urlsource::check_headers(line)?;
     }
 
     Ok(urls)
 }
 
+// Reads every line of the (possibly decompressed) input
+fn read_lines(reader: Box<dyn BufRead + Send>) -> Result<Vec<String>, Box<dyn Error>> {
+    reader
+        .lines()
+        .map(|line| line.map_err(|e| e.into()))
+        .collect()
+}
+
+// Reduces an absolute URL to its path, query and fragment
+fn strip_origin(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(parsed) => parsed[Position::BeforePath..].to_string(),
+        Err(_) => url.to_string(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -297,7 +1164,8 @@ mod tests {
     fn argparse_url_prefix() {
         let args = vec!["hb", "-p", "http://localhost", "/test"];
         let context = Config::from_cmdline(args).unwrap();
-        assert_eq!(&vec!("http://localhost/test".to_string()), context.urls);
+        assert_eq!(Some(1), context.urls.len());
+        assert_eq!("http://localhost/test", context.urls.fetch(0).unwrap().1);
     }
 
     // Verify we can parse the concurrency from the command line
@@ -320,13 +1188,41 @@ mod tests {
             "http://test",
         ];
         let context = Config::from_cmdline(args).unwrap();
-        let expected = Some(vec![
-            "Content-Type: application/json".to_string(),
-            "Authorization: Bearer Token".to_string(),
-        ]);
+        let expected = vec![
+            headers::parse("Content-Type: application/json", &HashSet::new()).unwrap(),
+            headers::parse("Authorization: Bearer Token", &HashSet::new()).unwrap(),
+        ];
         assert_eq!(expected, context.config.headers);
     }
 
+    // Verify an Authorization header can't be sent alongside one from an auth option
+    #[test]
+    fn argparse_auth_conflict() {
+        let args = vec![
+            "hb",
+            "-H",
+            "Authorization: Bearer Token",
+            "--bearer",
+            "Other",
+            "http://test",
+        ];
+        assert!(Config::from_cmdline(args).is_err());
+    }
+
+    // Verify request signing can't be combined with an Authorization header
+    #[test]
+    fn argparse_signing_conflict() {
+        let args = vec![
+            "hb",
+            "--hmac-key",
+            "k",
+            "-H",
+            "Authorization: Bearer t",
+            "http://test",
+        ];
+        assert!(Config::from_cmdline(args).is_err());
+    }
+
     // Verify that we prepend the URL prefix to any urls not currently prefixed with a valid scheme, host etc
     #[test]
     fn url_prefix_prepended() {
@@ -338,9 +1234,20 @@ mod tests {
             "/abc123?def=456".to_string(),
         ];
 
-        let loaded = load_urls(Some(&prefix), None, Some(urls)).unwrap();
+        let loaded =
+            load_urls(Some(&prefix), None, None, Some(urls), &UrlFilter::default()).unwrap();
         for test in loaded {
             assert_eq!(expected, test);
         }
     }
+
+    // Verify that sitemap URLs have their host rewritten when a prefix is supplied
+    #[test]
+    fn sitemap_host_rewritten() {
+        assert_eq!(
+            "/abc123?def=456",
+            strip_origin("https://www.example.com/abc123?def=456")
+        );
+        assert_eq!("/", strip_origin("https://www.example.com"));
+    }
 }
@@ -0,0 +1,291 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Gauge, Paragraph, Row, Sparkline, Table};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::requestgen::RequestGenerator;
+use crate::units::format_micros;
+use crate::workers::RunState;
+
+// How often the dashboard is redrawn (and checked for key presses)
+const REFRESH: Duration = Duration::from_millis(250);
+
+/// A full screen view of the test as it runs, drawn on its own thread until the workers finish
+pub(crate) struct Dashboard {
+    done: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Dashboard {
+    /// Takes over the terminal and starts drawing the dashboard
+    pub(crate) fn start(
+        state: Arc<RunState>,
+        request_generator: Arc<RequestGenerator>,
+        started: Instant,
+    ) -> Dashboard {
+        let done = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let done = done.clone();
+            thread::spawn(move || {
+                if let Err(e) = run(&state, &request_generator, started, &done) {
+                    error!("Unable to show the dashboard: {}", e);
+                }
+            })
+        };
+        Dashboard { done, thread }
+    }
+
+    /// Stops drawing the dashboard and gives the terminal back, ready for the results
+    pub(crate) fn finish(self) {
+        self.done.store(true, Ordering::Relaxed);
+        self.thread.join().unwrap();
+    }
+}
+
+fn run(
+    state: &RunState,
+    request_generator: &RequestGenerator,
+    started: Instant,
+    done: &AtomicBool,
+) -> std::io::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+
+    // Anything logged while the dashboard is up would be drawn over it, so hold off until it's gone
+    let log_level = log::max_level();
+    log::set_max_level(log::LevelFilter::Off);
+
+    let drawn = draw_until_done(&mut terminal, state, request_generator, started, done);
+
+    ratatui::restore();
+    log::set_max_level(log_level);
+    drawn
+}
+
+fn draw_until_done(
+    terminal: &mut DefaultTerminal,
+    state: &RunState,
+    request_generator: &RequestGenerator,
+    started: Instant,
+    done: &AtomicBool,
+) -> std::io::Result<()> {
+    let mut stopping = false;
+    while !done.load(Ordering::Relaxed) {
+        let (sent, total) = {
+            let progress = request_generator.progress.lock().unwrap();
+            (progress.position(), progress.length().unwrap_or(0))
+        };
+        let snapshot = Snapshot::take(state, started.elapsed(), sent, total);
+        terminal.draw(|frame| draw(frame, &snapshot, stopping))?;
+
+        // The terminal is in raw mode, so Ctrl-C arrives as a key press rather than a signal
+        if event::poll(REFRESH)?
+            && let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+        {
+            let ctrl_c =
+                key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            if ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                request_generator.stop();
+                stopping = true;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What the dashboard shows at a point in the test
+#[derive(Debug, PartialEq)]
+struct Snapshot {
+    elapsed: Duration,
+    sent: u64,
+    total: u64,
+    in_flight: u32,
+    // Figures for the last interval the workers have all finished with, as later ones are still being filled in
+    requests_per_sec: f64,
+    error_rate: f64,
+    p50: u64,
+    p99: u64,
+    // Responses by status, followed by requests that failed without a response
+    status: Vec<(String, u64)>,
+    // The p99 latency of each finished interval so far
+    p99_history: Vec<u64>,
+}
+
+impl Snapshot {
+    fn take(state: &RunState, elapsed: Duration, sent: u64, total: u64) -> Snapshot {
+        let mut status = state
+            .responses
+            .as_ref()
+            .map(|responses| {
+                responses
+                    .lock()
+                    .unwrap()
+                    .status
+                    .iter()
+                    .map(|(code, count)| (code.to_string(), *count))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let failures = state.failures.load(Ordering::Relaxed);
+        if failures > 0 {
+            status.push(("failed".to_string(), failures));
+        }
+
+        // Workers only add an interval to the timeline when they finish a request in the next one, so the interval that
+        // just ended may still be missing requests. Show the one before it, which they've all moved on from
+        let timeline = state.timeline.lock().unwrap();
+        let complete = ((elapsed.as_nanos() / timeline.interval.as_nanos()) as usize)
+            .saturating_sub(1)
+            .min(timeline.intervals.len());
+        let intervals = &timeline.intervals[..complete];
+        let (requests_per_sec, error_rate, p50, p99) = match intervals.last() {
+            Some(last) if last.requests > 0 => (
+                last.requests as f64 / timeline.interval.as_secs_f64(),
+                last.errors as f64 / last.requests as f64,
+                last.latency.value_at_percentile(50.0),
+                last.latency.value_at_percentile(99.0),
+            ),
+            _ => (0.0, 0.0, 0, 0),
+        };
+
+        Snapshot {
+            elapsed,
+            sent,
+            total,
+            in_flight: state.in_flight.load(Ordering::Relaxed),
+            requests_per_sec,
+            error_rate,
+            p50,
+            p99,
+            status,
+            p99_history: intervals
+                .iter()
+                .map(|interval| interval.latency.value_at_percentile(99.0))
+                .collect(),
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, snapshot: &Snapshot, stopping: bool) {
+    let [progress_area, now_area, detail_area] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(3),
+        Constraint::Min(5),
+    ])
+    .areas(frame.area());
+    let [status_area, latency_area] =
+        Layout::horizontal([Constraint::Length(24), Constraint::Min(20)]).areas(detail_area);
+
+    // Progress through the requests
+    let title = match stopping {
+        true => " hb - stopping, waiting for requests in flight ".to_string(),
+        false => format!(
+            " hb - {}s elapsed - press q to stop ",
+            snapshot.elapsed.as_secs()
+        ),
+    };
+    let ratio = match snapshot.total {
+        0 => 0.0,
+        total => (snapshot.sent as f64 / total as f64).min(1.0),
+    };
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title(title))
+            .gauge_style(Style::default().fg(Color::Cyan))
+            .ratio(ratio)
+            .label(format!("{}/{}", snapshot.sent, snapshot.total)),
+        progress_area,
+    );
+
+    // How things are going right now
+    let error_style = match snapshot.error_rate {
+        0.0 => Style::default(),
+        _ => Style::default().fg(Color::Red),
+    };
+    let now = Line::from(vec![
+        format!("{:.1} req/s", snapshot.requests_per_sec).into(),
+        "   in flight ".into(),
+        snapshot.in_flight.to_string().into(),
+        "   errors ".into(),
+        Span::styled(format!("{:.2}%", snapshot.error_rate * 100.0), error_style),
+        "   p50 ".into(),
+        format_micros(snapshot.p50).into(),
+        "   p99 ".into(),
+        format_micros(snapshot.p99).into(),
+    ]);
+    frame.render_widget(
+        Paragraph::new(now).block(Block::bordered().title(" Previous interval ")),
+        now_area,
+    );
+
+    // Responses so far
+    let rows = snapshot
+        .status
+        .iter()
+        .map(|(status, count)| Row::new(vec![status.clone(), count.to_string()]));
+    frame.render_widget(
+        Table::new(rows, [Constraint::Length(8), Constraint::Min(8)])
+            .header(Row::new(vec!["Status", "Count"]).style(Style::default().fg(Color::Yellow)))
+            .block(Block::bordered().title(" Responses ")),
+        status_area,
+    );
+
+    // Latency over the run, keeping the most recent intervals if they don't all fit
+    let width = latency_area.width.saturating_sub(2) as usize;
+    let history = &snapshot.p99_history[snapshot.p99_history.len().saturating_sub(width)..];
+    let max = history.iter().copied().max().unwrap_or(0);
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::bordered().title(format!(" p99 latency (max {}) ", format_micros(max))))
+            .style(Style::default().fg(Color::Green))
+            .data(history.iter().copied()),
+        latency_area,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workers::OutcomeClass;
+    use crate::workers::timeline::{IntervalRecorder, Timeline};
+
+    // Verify the snapshot shows the last finished interval and the responses so far
+    #[test]
+    fn snapshot_of_last_interval() {
+        let state = RunState::new(1, Timeline::new(Duration::from_secs(1)), true);
+        let mut recorder = IntervalRecorder::new(state.timeline.clone(), Instant::now());
+        recorder.record(1_000, OutcomeClass::Success, None);
+        recorder.record(3_000, OutcomeClass::ServerError, None);
+        recorder.flush();
+        let mut responses = state.responses.as_ref().unwrap().lock().unwrap();
+        responses.status.insert(200, 1);
+        responses.status.insert(503, 1);
+        drop(responses);
+        state.failures.fetch_add(2, Ordering::Relaxed);
+
+        // The first interval has only just ended, so the workers may not have added all of it yet
+        let snapshot = Snapshot::take(&state, Duration::from_millis(1500), 4, 10);
+        assert_eq!(0.0, snapshot.requests_per_sec);
+        assert!(snapshot.p99_history.is_empty());
+
+        let snapshot = Snapshot::take(&state, Duration::from_millis(2500), 4, 10);
+        assert_eq!(2.0, snapshot.requests_per_sec);
+        assert_eq!(0.5, snapshot.error_rate);
+        assert_eq!(1, snapshot.p99_history.len());
+        assert_eq!(
+            vec![
+                ("200".to_string(), 1),
+                ("503".to_string(), 1),
+                ("failed".to_string(), 2)
+            ],
+            snapshot.status
+        );
+    }
+}
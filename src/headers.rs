@@ -0,0 +1,150 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+
+use crate::template::Template;
+
+/// A header added to requests. The value may contain template placeholders
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Header {
+    pub name: String,
+    pub value: Template,
+}
+
+/// A header as sent with a request. Names and values are borrowed from the config where they don't vary per request
+pub(crate) type RequestHeader<'a> = (Cow<'a, str>, Cow<'a, str>);
+
+/// Parses the -H arguments, preserving their order and any repeated names. Arguments of the form @file read the
+/// headers from a file, one per line (ignoring blank lines and # comments)
+pub(crate) fn parse_args<'a, I>(
+    args: I,
+    variables: &HashSet<String>,
+) -> Result<Vec<Header>, Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let mut headers = Vec::new();
+    for arg in args {
+        if let Some(path) = arg.strip_prefix('@') {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read headers from {}: {}", path, e))?;
+            for line in contents.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                headers.push(parse(line, variables)?);
+            }
+        } else {
+            headers.push(parse(arg, variables)?);
+        }
+    }
+
+    Ok(headers)
+}
+
+/// Parses a single "Name: value" header. The value may itself contain colons, and refer to the nominated variables
+pub(crate) fn parse(header: &str, variables: &HashSet<String>) -> Result<Header, Box<dyn Error>> {
+    let (name, value) = parse_raw(header)?;
+    Ok(Header {
+        name: name.to_string(),
+        value: Template::parse(value, variables),
+    })
+}
+
+/// Parses the headers that follow the URL on a line of the URL file (tab separated). These are sent as-is
+pub(crate) fn parse_inline(headers: &str) -> Result<Vec<(&str, &str)>, Box<dyn Error>> {
+    headers
+        .split('\t')
+        .filter(|h| !h.trim().is_empty())
+        .map(parse_raw)
+        .collect()
+}
+
+/// Splits the headers that follow the URL on a line of the URL file, which parse_inline has already checked as the URLs
+/// were read
+pub(crate) fn split_inline(headers: &str) -> impl Iterator<Item = (&str, &str)> {
+    headers
+        .split('\t')
+        .filter_map(|header| header.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+}
+
+// Splits the header at the first colon, validating the name and value
+fn parse_raw(header: &str) -> Result<(&str, &str), Box<dyn Error>> {
+    let Some((name, value)) = header.split_once(':') else {
+        return Err(format!("Invalid header '{}': expected 'Name: value'", header).into());
+    };
+
+    // Header names are tokens: visible ASCII excluding separators
+    let name = name.trim();
+    let valid_name = !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !b"\"(),/:;<=>?@[\\]{}".contains(&b));
+    if !valid_name {
+        return Err(format!("Invalid header name in '{}'", header).into());
+    }
+
+    let value = value.trim();
+    if value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0) {
+        return Err(format!("Invalid header value in '{}'", header).into());
+    }
+
+    Ok((name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify values containing colons are preserved intact, as are duplicated names
+    #[test]
+    fn parse_headers() {
+        let args = [
+            "Authorization: Basic a:b".to_string(),
+            "Referer:http://example.com:8080/".to_string(),
+            "X-Dup: 1".to_string(),
+            "X-Dup: 2".to_string(),
+        ];
+        let headers = parse_args(&args, &HashSet::new()).unwrap();
+        let pairs = headers
+            .iter()
+            .map(|h| (h.name.as_str(), h.value.clone()))
+            .collect::<Vec<_>>();
+        let expected = [
+            ("Authorization", "Basic a:b"),
+            ("Referer", "http://example.com:8080/"),
+            ("X-Dup", "1"),
+            ("X-Dup", "2"),
+        ]
+        .map(|(n, v)| (n, Template::parse(v, &HashSet::new())));
+        assert_eq!(expected.to_vec(), pairs);
+    }
+
+    // Verify malformed headers are reported rather than panicking
+    #[test]
+    fn parse_malformed() {
+        let variables = HashSet::new();
+        assert!(parse("no colon", &variables).is_err());
+        assert!(parse(": empty name", &variables).is_err());
+        assert!(parse("Bad Name: x", &variables).is_err());
+        assert!(parse_args(&["@/does/not/exist".to_string()], &variables).is_err());
+    }
+
+    // Verify per-request headers are split from the tab separated URL line
+    #[test]
+    fn parse_inline_headers() {
+        let expected = vec![("Accept", "text/html"), ("Cookie", "a=b; c=d")];
+        assert_eq!(
+            expected,
+            parse_inline("Accept: text/html\tCookie: a=b; c=d\t").unwrap()
+        );
+        assert_eq!(
+            expected,
+            split_inline("Accept: text/html\tCookie: a=b; c=d\t").collect::<Vec<_>>()
+        );
+        assert!(parse_inline("Accept text/html").is_err());
+    }
+}
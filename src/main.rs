@@ -1,91 +1,247 @@
 #[macro_use]
 extern crate log;
 
-use crate::config::{HttpMethod, LoadTestContext};
-use crate::workers::BenchResult;
-use std::collections::HashMap;
+use crate::compare::Comparison;
+use crate::config::{CompareConfig, HttpMethod, LoadTestContext};
+use crate::report::{Report, RunConfig, SlowRequests, Throughput, URL_LATENCY_LIMIT, UrlLatency};
+use crate::threshold::{ThresholdMonitor, ThresholdResult};
+use crate::units::{format_bytes, format_micros};
+use crate::workers::phases::Connection;
+use crate::workers::timeline::Timeline;
+use crate::workers::{BenchResult, RequestOptions, RunState};
+use hdrhistogram::Histogram;
 use std::error::Error;
 use std::fs::File;
-use std::time::{Duration, Instant};
+use std::io::BufWriter;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{env, io};
 use ureq::Agent;
 
+mod auth;
+mod compare;
 mod config;
+mod dashboard;
+mod headers;
+mod report;
 mod requestgen;
+mod signing;
+mod template;
+mod threshold;
+mod units;
+mod urlsource;
 mod workers;
 
+// The exit status when the run fails a threshold or regresses from the baseline, so it can be told apart from hb
+// itself failing
+const RUN_FAILED: u8 = 2;
+
 /// Parses command line arguments, launches the workers, consolidates results
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> Result<ExitCode, Box<dyn Error>> {
     // Initialise logging
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
         .init();
 
-    // Parse the command line and read in the set of URLs we use to test
+    // Comparing two earlier runs doesn't need anything else
     let args: Vec<String> = env::args().collect();
+    if args.get(1).is_some_and(|arg| arg == "compare") {
+        return compare(&args[1..]);
+    }
+
+    // Parse the command line and read in the set of URLs we use to test
     let LoadTestContext {
         config,
         urls,
         payloads,
     } = config::Config::from_cmdline(args)?;
 
+    // Read the baseline up front, so a bad file doesn't waste a run
+    let baseline = config.baseline.as_deref().map(compare::load).transpose()?;
+
     // Check TIME_WAIT
     check_time_wait()?;
 
     // When testing POST or PUT, the total number of distinct requests should be the size of payloads list
     let distinct_requests_count = match config.http_method {
         HttpMethod::Post | HttpMethod::Put => payloads.len(),
-        _ => urls.len(),
+        // Streaming sources hand out the lines of the file in order themselves, so the index just numbers the requests
+        _ => urls.len().unwrap_or(config.requests),
     };
 
+    // Keep a record of the config for the machine readable results, before parts of it are handed to the workers
+    let run_config = RunConfig::new(&config, urls.len());
+
     // Initialise the request generator from the config
-    let request_generator = requestgen::RequestGenerator::new(&config, distinct_requests_count);
+    let request_generator = Arc::new(requestgen::RequestGenerator::new(
+        &config,
+        distinct_requests_count,
+    ));
 
-    // Build the headers added to each request
-    let header_map = config.headers.map(|headers| {
-        let mut map = HashMap::new();
+    // Initialise the ureq agent (shared connection pool etc)
+    let agent_config = Agent::config_builder()
+        .max_idle_connections_per_host(config.concurrency as usize)
+        // Error statuses are responses like any other, so we time them and read their bodies
+        .http_status_as_error(false)
+        // Redirects aren't followed: each request is a single exchange, and sessions keep cookies set on redirects
+        .max_redirects(0)
+        .timeout_connect(config.timeouts.connect)
+        .timeout_recv_response(config.timeouts.first_byte)
+        .timeout_recv_body(config.timeouts.body)
+        .timeout_global(config.timeouts.overall)
+        .build();
+    // Hook the resolver and connectors so we can time each phase of the requests
+    let agent = Agent::with_parts(
+        agent_config,
+        workers::phases::connector(),
+        workers::phases::TimedResolver::default(),
+    );
 
-        for header_str in headers {
-            let mut iter = header_str.split(':');
-            let header = iter.next().unwrap().trim().to_string();
-            let value = iter.next().unwrap().trim().to_string();
-            map.insert(header, value);
-        }
+    // The interval log needs each interval broken down further, which takes a lot more memory so is only done on request
+    let timeline = match config.hlog_file {
+        Some(_) => Timeline::new(config.interval).tagged(),
+        None => Timeline::new(config.interval),
+    };
 
-        map
-    });
+    // The live view of the responses is only needed by the dashboard and the threshold monitor
+    let watched = config.dashboard || config.abort_on_threshold;
+    let state = Arc::new(RunState::new(config.concurrency, timeline, watched));
 
-    // Initialise the ureq agent (shared connection pool etc)
-    let agent: Agent = Agent::config_builder()
-        .max_idle_connections_per_host(config.concurrency as usize)
-        .build()
-        .new_agent();
+    // Stop the test early if a threshold is breached
+    let monitor = config.abort_on_threshold.then(|| {
+        ThresholdMonitor::start(
+            config.thresholds.clone(),
+            run_config.requests as u64,
+            state.clone(),
+            request_generator.clone(),
+        )
+    });
 
     // Launch the workers
-    let bench_start = Instant::now();
     info!("Running test");
 
     let result_summary = workers::run_test(
         agent,
-        config.http_method,
-        header_map,
+        RequestOptions {
+            http_method: config.http_method,
+            headers: config.headers,
+            auth: config.auth,
+            signer: config.signer,
+            session: config.session,
+            setup: config.setup,
+            assertions: Arc::new(config.assertions),
+            retry: config.retry,
+            discard_body: config.discard_body,
+        },
         config.concurrency,
         request_generator,
         urls,
         payloads,
+        state,
     );
-    let bench_end = Instant::now();
+    if let Some(monitor) = monitor {
+        monitor.finish();
+    }
 
-    // Print the results of the benchmark
-    let bench_duration = bench_end.duration_since(bench_start);
-    print_results(bench_duration, &result_summary);
+    // Generate a report of slow requests if required
+    let slow_requests = config
+        .slow_percentile
+        .map(|slow_percentile| SlowRequests::new(&result_summary, urls, slow_percentile))
+        .transpose()?;
 
-    // Generate a report if required
-    if let Some(slow_percentile) = config.slow_percentile {
-        print_slow_report(result_summary, urls, slow_percentile);
+    // Check the results against any thresholds
+    let thresholds = config
+        .thresholds
+        .iter()
+        .map(|threshold| threshold.evaluate(&result_summary))
+        .collect::<Vec<_>>();
+    let passed = thresholds.iter().all(|threshold| threshold.passed);
+
+    // Print the results of the benchmark, unless they're being written to stdout in a machine readable format instead
+    if config.output.is_none() || config.output_file.is_some() {
+        print_results(result_summary.run_time, &result_summary);
+        if let Some(slow_requests) = &slow_requests {
+            print_slow_report(slow_requests);
+        }
+        print_thresholds(&thresholds);
     }
+    let mut regressions = Vec::new();
+    if config.output.is_some() || baseline.is_some() {
+        let url_latency = UrlLatency::most_requested(&result_summary, urls, URL_LATENCY_LIMIT)?;
+        let report = Report::new(
+            run_config,
+            &result_summary,
+            slow_requests,
+            url_latency,
+            thresholds,
+        )?;
 
-    Ok(())
+        if let (Some(baseline), Some(path)) = (&baseline, &config.baseline) {
+            let comparison = Comparison::new(baseline, &report, &config.tolerances)?;
+            if config.output.is_none() || config.output_file.is_some() {
+                comparison.print(path);
+            }
+            regressions = comparison
+                .regressions()
+                .into_iter()
+                .map(str::to_string)
+                .collect();
+        }
+
+        if let Some(format) = config.output {
+            match &config.output_file {
+                Some(path) => {
+                    info!("Writing results to {}", path);
+                    report.write(format, &mut BufWriter::new(File::create(path)?))?;
+                }
+                None => report.write(format, &mut io::stdout().lock())?,
+            }
+        }
+    }
+
+    if let Some(path) = &config.timeline_file {
+        info!("Writing timeline to {}", path);
+        result_summary.timeline.write(path)?;
+    }
+    if let Some(path) = &config.hlog_file {
+        info!("Writing interval log to {}", path);
+        result_summary.timeline.write_hlog(path)?;
+    }
+
+    if !regressions.is_empty() {
+        warn!(
+            "*** Regressed from the baseline: {}",
+            regressions.join(", ")
+        );
+    }
+    if !passed {
+        warn!("*** The run failed at least one threshold");
+    }
+    match passed && regressions.is_empty() {
+        true => Ok(ExitCode::SUCCESS),
+        false => Ok(ExitCode::from(RUN_FAILED)),
+    }
+}
+
+// Compares two results files, failing if the current results have regressed from the baseline
+fn compare(args: &[String]) -> Result<ExitCode, Box<dyn Error>> {
+    let config = CompareConfig::from_cmdline(args)?;
+    let baseline = compare::load(&config.baseline)?;
+    let current = compare::load(&config.current)?;
+
+    let comparison = Comparison::new(&baseline, &current, &config.tolerances)?;
+    comparison.print(&config.baseline);
+
+    let regressions = comparison.regressions();
+    if regressions.is_empty() {
+        return Ok(ExitCode::SUCCESS);
+    }
+    warn!(
+        "*** Regressed from the baseline: {}",
+        regressions.join(", ")
+    );
+    Ok(ExitCode::from(RUN_FAILED))
 }
 
 fn check_time_wait() -> Result<(), Box<dyn Error>> {
@@ -104,59 +260,40 @@ fn check_time_wait() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-// Output the report
-fn print_slow_report(summary: BenchResult, urls: &[String], slow_percentile: f64) {
-    // Collect all the durations by URL
-    let mut url_stats = HashMap::new();
-    for (url_index, duration) in summary.request_times {
-        let url = urls[url_index].as_str();
-        url_stats.entry(url).or_insert_with(Vec::new).push(duration);
+// Output whether the run met each threshold
+fn print_thresholds(thresholds: &[ThresholdResult]) {
+    if thresholds.is_empty() {
+        return;
     }
 
-    // Determine the lower latency bound for the request to be included in the slow requests report
-    let lower_bound = summary.latency.value_at_percentile(slow_percentile);
-
-    // Compute the summary stats by URL and filter to those that exceed the smallest latency cutoff
-    let mut lines = url_stats
-        .iter()
-        .filter_map(|(url, durations)| {
-            // Compute the basic stats we need for the report line
-            let (min, max, sum) = durations.iter().fold((u64::MAX, 0, 0), |state, &duration| {
-                (
-                    state.0.min(duration),
-                    state.1.max(duration),
-                    state.2 + duration,
-                )
-            });
-
-            // If the max latency didn't exceed our lower bound then we just ignore this URL for the report
-            if max < lower_bound {
-                return None;
-            }
-
-            let count = durations.len();
-            let avg = sum / count as u64;
-            Some(ReportLine {
-                url,
-                count,
-                min,
-                max,
-                avg,
-            })
-        })
-        .collect::<Vec<ReportLine>>();
-
-    // Sort by latency in descending order and dump out the report
-    lines.sort_by_key(|rl| std::cmp::Reverse(rl.max));
+    println!("\nThresholds:\nresult\tactual\t\tthreshold");
+    for threshold in thresholds {
+        let result = match threshold.passed {
+            true => "pass",
+            false => "FAIL",
+        };
+        println!(
+            "{}\t{:<16}{}",
+            result, threshold.actual, threshold.threshold
+        );
+    }
+}
 
+// Output the report
+fn print_slow_report(slow: &SlowRequests) {
     println!(
-        "\nSlow requests ({}%'ile -> {}ms):\nmax\tavg\tmin\tcount\trequest",
-        slow_percentile, lower_bound
+        "\nSlow requests ({}%'ile -> {}):\nmax\tavg\tmin\tcount\trequest",
+        slow.percentile,
+        format_micros(slow.threshold_us)
     );
-    for line in lines {
+    for url in &slow.urls {
         println!(
             "{}\t{}\t{}\t{}\t{}",
-            line.max, line.avg, line.min, line.count, line.url
+            format_micros(url.max_us),
+            format_micros(url.avg_us),
+            format_micros(url.min_us),
+            url.count,
+            url.url
         );
     }
 }
@@ -170,6 +307,27 @@ fn print_results(bench_duration: Duration, summary: &BenchResult) {
     if summary.response_errors > 0 {
         warn!("*** {} response errors", summary.response_errors);
     }
+    if summary.setup_errors > 0 {
+        warn!("*** {} workers failed setup", summary.setup_errors);
+    }
+
+    if summary.assertion_failures > 0 {
+        warn!(
+            "*** {} responses failed assertions",
+            summary.assertion_failures
+        );
+    }
+
+    if summary.retries > 0 {
+        warn!(
+            "*** {} retries across {} requests, {} of which still failed after the last retry",
+            summary.retries, summary.retried_requests, summary.retries_exhausted
+        );
+    }
+
+    if summary.sessions > 0 {
+        info!("{} sessions started", summary.sessions);
+    }
 
     // Dump the status codes
     let mut codes = summary.status.keys().copied().collect::<Vec<u16>>();
@@ -179,23 +337,138 @@ fn print_results(bench_duration: Duration, summary: &BenchResult) {
         println!("{}\t{}", code, summary.status.get(&code).unwrap());
     }
 
-    // Dump the latency
+    // Dump the transport errors, most frequent first
+    if !summary.transport_errors.is_empty() {
+        let mut errors = summary.transport_errors.iter().collect::<Vec<_>>();
+        errors.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        println!("\nTransport errors:");
+        for (error, count) in errors {
+            println!("{}\t{}", count, error);
+        }
+    }
+
+    // Dump the assertion failures, most frequent first
+    if !summary.failed_assertions.is_empty() {
+        let mut failures = summary.failed_assertions.iter().collect::<Vec<_>>();
+        failures.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        println!("\nFailed assertions:");
+        for (assertion, count) in failures {
+            println!("{}\t{}", count, assertion);
+        }
+    }
+
+    // Dump the setup latency separately from the test itself
+    if !summary.setup_latency.is_empty() {
+        println!(
+            "\nSetup latency ({} requests):",
+            summary.setup_latency.len()
+        );
+        print_percentiles(&summary.setup_latency);
+    }
+
+    print_throughput(bench_duration, summary);
+
+    // Dump the latency, then break it down by the class of response
     println!(
         "\nBenchmark run time {}s.\nLatency:",
         bench_duration.as_secs_f32()
     );
+    print_percentiles(&summary.latency);
+    for (class, latency) in &summary.class_latency {
+        println!("\n{} latency ({} requests):", class, latency.len());
+        print_percentiles(latency);
+    }
+
+    print_phases(summary);
+
+    print_timeline(&summary.timeline);
+
+    // With retries, compare the first attempts with what the client saw overall
+    if !summary.end_to_end_latency.is_empty() {
+        println!("\nFirst attempt latency:");
+        print_percentiles(&summary.first_attempt_latency);
+        println!("\nEnd-to-end latency (including retries):");
+        print_percentiles(&summary.end_to_end_latency);
+    }
+}
+
+// Output a compact view of how the request rate, errors and latency changed over the run
+fn print_timeline(timeline: &Timeline) {
+    if timeline.intervals.len() < 2 {
+        return;
+    }
+
+    println!("\nTimeline:\ntime\trequests\terrors\t50%\t99%\tmax");
+    for (start, interval) in timeline.compact(20) {
+        println!(
+            "{}s\t{}\t\t{}\t{}\t{}\t{}",
+            start.as_secs(),
+            interval.requests,
+            interval.errors,
+            format_micros(interval.latency.value_at_percentile(50.0)),
+            format_micros(interval.latency.value_at_percentile(99.0)),
+            format_micros(interval.latency.max())
+        );
+    }
+}
+
+// Output a compact table of the time spent in each phase of the requests, for new and reused connections
+fn print_phases(summary: &BenchResult) {
+    for connection in [Connection::New, Connection::Reused] {
+        let phases = summary
+            .phase_latency
+            .iter()
+            .filter(|((c, _), _)| *c == connection)
+            .collect::<Vec<_>>();
+        // Failed requests may not have reached every phase
+        let Some(requests) = phases.iter().map(|(_, latency)| latency.len()).max() else {
+            continue;
+        };
+
+        println!("\nPhases for {} ({} requests):", connection, requests);
+        println!("phase\t\t50%\t95%\t99%\t100%");
+        for ((_, phase), latency) in phases {
+            let label = format!("{}", phase);
+            println!(
+                "{:<16}{}\t{}\t{}\t{}",
+                label,
+                format_micros(latency.value_at_percentile(50.0)),
+                format_micros(latency.value_at_percentile(95.0)),
+                format_micros(latency.value_at_percentile(99.0)),
+                format_micros(latency.max())
+            );
+        }
+    }
+}
+
+// Output the request rate and bytes transferred, as a mean over the run and the peak in any one second
+fn print_throughput(bench_duration: Duration, summary: &BenchResult) {
+    let throughput = Throughput::new(bench_duration, summary);
+
+    println!("\nThroughput (bytes are request and response bodies):");
+    println!(
+        "requests\t{}\t{:.1}/s mean\t{}/s peak",
+        throughput.requests, throughput.requests_per_sec, throughput.peak_requests_per_sec
+    );
+    println!(
+        "received\t{}\t{}/s mean\t{}/s peak",
+        format_bytes(throughput.bytes_received as f64),
+        format_bytes(throughput.received_per_sec),
+        format_bytes(throughput.peak_received_per_sec as f64)
+    );
+    println!(
+        "sent\t\t{}\t{}/s mean\t{}/s peak",
+        format_bytes(throughput.bytes_sent as f64),
+        format_bytes(throughput.sent_per_sec),
+        format_bytes(throughput.peak_sent_per_sec as f64)
+    );
+}
+
+fn print_percentiles(latency: &Histogram<u64>) {
     for p in &[
         50f64, 75f64, 95f64, 99f64, 99.9f64, 99.99f64, 99.999f64, 100f64,
     ] {
-        let millis = &summary.latency.value_at_percentile(*p);
-        println!("{}%\t{}ms", p, millis);
+        let micros = latency.value_at_percentile(*p);
+        println!("{}%\t{}", p, format_micros(micros));
     }
 }
-
-struct ReportLine<'a> {
-    url: &'a str,
-    count: usize,
-    min: u64,
-    max: u64,
-    avg: u64,
-}
@@ -0,0 +1,189 @@
+use std::error::Error;
+use std::io::Write;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::report::Report;
+
+const HEADER: &str = "section,name,metric,value";
+
+/// Writes the report as one row per figure (section,name,metric,value), so it can be loaded straight into a
+/// spreadsheet or dashboard and filtered
+pub(crate) fn write(report: &Report, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let mut rows = Rows::default();
+    rows.add("run", "", "schema_version", report.schema_version);
+    rows.add("run", "", "hb_version", &report.hb_version);
+    rows.add("run", "", "run_time_secs", report.run_time_secs);
+    rows.add_fields("config", "", &report.config)?;
+    for (status, count) in &report.status {
+        rows.add("status", status, "count", count);
+    }
+    rows.add_fields("errors", "", &report.errors)?;
+    rows.add_fields("retries", "", &report.retries)?;
+    rows.add_fields("throughput", "", &report.throughput)?;
+
+    // Every latency summary has the same metrics, named after the type of request
+    rows.add_fields("latency", "all", &report.latency)?;
+    for point in &report.latency_distribution {
+        rows.add(
+            "latency_distribution",
+            point.percentile,
+            "latency_us",
+            point.latency_us,
+        );
+    }
+    for (class, latency) in &report.class_latency {
+        rows.add_fields("latency", class, latency)?;
+    }
+    let extra = [
+        ("setup", &report.setup_latency),
+        ("first attempt", &report.first_attempt_latency),
+        ("end to end", &report.end_to_end_latency),
+    ];
+    for (name, latency) in extra {
+        if let Some(latency) = latency {
+            rows.add_fields("latency", name, latency)?;
+        }
+    }
+    for phase in &report.phase_latency {
+        let name = format!("{} {}", phase.connection, phase.phase);
+        rows.add_fields("phase_latency", &name, &phase.latency)?;
+    }
+    for url in &report.url_latency {
+        rows.add_fields("url_latency", &url.url, &url.latency)?;
+    }
+
+    for interval in &report.timeline {
+        rows.add_fields("timeline", interval.start_secs, interval)?;
+    }
+    if let Some(slow) = &report.slow_requests {
+        rows.add("slow_requests", "", "percentile", slow.percentile);
+        rows.add("slow_requests", "", "threshold_us", slow.threshold_us);
+        for url in &slow.urls {
+            rows.add_fields("slow_requests", &url.url, url)?;
+        }
+    }
+
+    for threshold in &report.thresholds {
+        rows.add(
+            "thresholds",
+            &threshold.threshold,
+            "actual",
+            &threshold.actual,
+        );
+        rows.add(
+            "thresholds",
+            &threshold.threshold,
+            "passed",
+            threshold.passed,
+        );
+    }
+
+    writeln!(out, "{}", HEADER)?;
+    for row in rows.0 {
+        writeln!(out, "{}", row.map(|field| quote(&field)).join(","))?;
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Rows(Vec<[String; 4]>);
+
+impl Rows {
+    fn add(&mut self, section: &str, name: impl ToString, metric: &str, value: impl ToString) {
+        self.0.push([
+            section.to_string(),
+            name.to_string(),
+            metric.to_string(),
+            value.to_string(),
+        ]);
+    }
+
+    // Adds a row for each field of the value, naming nested fields with dots e.g. transport.connect failed
+    fn add_fields(
+        &mut self,
+        section: &str,
+        name: impl ToString,
+        value: &impl Serialize,
+    ) -> Result<(), Box<dyn Error>> {
+        let name = name.to_string();
+        let mut fields = Vec::new();
+        flatten("", &serde_json::to_value(value)?, &mut fields);
+        for (metric, value) in fields {
+            self.add(section, &name, &metric, value);
+        }
+        Ok(())
+    }
+}
+
+fn flatten(prefix: &str, value: &Value, fields: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                let key = match prefix {
+                    "" => key.clone(),
+                    _ => format!("{}.{}", prefix, key),
+                };
+                flatten(&key, value, fields);
+            }
+        }
+        Value::Array(values) => {
+            let values = values.iter().map(plain).collect::<Vec<_>>();
+            fields.push((prefix.to_string(), values.join("; ")));
+        }
+        _ => fields.push((prefix.to_string(), plain(value))),
+    }
+}
+
+// A value as it should appear in a cell, without the quotes JSON puts around strings
+fn plain(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        _ => value.to_string(),
+    }
+}
+
+// Quotes a field if it contains anything that would break the CSV
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify nested values are flattened and awkward fields quoted
+    #[test]
+    fn fields_flattened_and_quoted() {
+        let value = serde_json::json!({
+            "count": 3,
+            "transport": {"connect failed": 2},
+            "assertions": ["status 200", "body contains \"ok\""],
+            "timeout_ms": null
+        });
+        let mut fields = Vec::new();
+        flatten("", &value, &mut fields);
+        assert_eq!(
+            vec![
+                (
+                    "assertions".to_string(),
+                    "status 200; body contains \"ok\"".to_string()
+                ),
+                ("count".to_string(), "3".to_string()),
+                ("timeout_ms".to_string(), String::new()),
+                ("transport.connect failed".to_string(), "2".to_string()),
+            ],
+            fields
+        );
+
+        assert_eq!("plain", quote("plain"));
+        assert_eq!("\"a,b\"", quote("a,b"));
+        assert_eq!("\"say \"\"hi\"\"\"", quote("say \"hi\""));
+    }
+}
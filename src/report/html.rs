@@ -0,0 +1,58 @@
+use std::error::Error;
+use std::io::Write;
+
+use crate::report::Report;
+
+// The page, with the charting script inline so the report works offline
+const TEMPLATE: &str = include_str!("report.html");
+
+/// Writes the report as a single HTML page with charts, which needs nothing else to view
+pub(crate) fn write(report: &Report, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    // A URL containing </script> must not end the data block early
+    let data = serde_json::to_string(report)?.replace("</", "<\\/");
+    out.write_all(TEMPLATE.replace("{{report}}", &data).as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::tests::run_config;
+    use crate::report::{SlowRequests, SlowUrl};
+    use crate::workers::BenchResult;
+
+    // Verify the report is embedded as JSON that a URL can't break out of
+    #[test]
+    fn data_embedded() {
+        let mut summary = BenchResult::new();
+        summary.status.insert(200, 1);
+        let slow = SlowRequests {
+            percentile: 99.0,
+            threshold_us: 1_000,
+            urls: vec![SlowUrl {
+                url: "/a?q=</script><script>alert(1)</script>".to_string(),
+                count: 1,
+                min_us: 1_000,
+                avg_us: 1_000,
+                max_us: 1_000,
+            }],
+        };
+        let report =
+            Report::new(run_config(), &summary, Some(slow), Vec::new(), Vec::new()).unwrap();
+
+        let mut html = Vec::new();
+        write(&report, &mut html).unwrap();
+        let html = String::from_utf8(html).unwrap();
+
+        // The data block runs to the first </script>, and is the whole report
+        let start = "<script type=\"application/json\" id=\"report\">";
+        let data = &html[html.find(start).unwrap() + start.len()..];
+        let data = &data[..data.find("</script>").unwrap()];
+        let embedded: Report = serde_json::from_str(data).unwrap();
+        assert_eq!(
+            "/a?q=</script><script>alert(1)</script>",
+            embedded.slow_requests.unwrap().urls[0].url
+        );
+        assert_eq!(Some(&1), embedded.status.get(&200));
+    }
+}
@@ -0,0 +1,235 @@
+use std::error::Error;
+use std::io::Write;
+
+use crate::report::{Percentiles, Report};
+use crate::units::{format_bytes, format_micros};
+
+/// Writes the report as Markdown tables, e.g. for a pull request comment
+pub(crate) fn write(report: &Report, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let config = &report.config;
+    let throughput = &report.throughput;
+    writeln!(out, "## hb results\n")?;
+    writeln!(out, "| | |\n|---|---|")?;
+    writeln!(out, "| Run time | {:.2}s |", report.run_time_secs)?;
+    writeln!(
+        out,
+        "| Load | {} {} requests across {} URLs from {} workers |",
+        config.requests,
+        config.method,
+        config
+            .urls
+            .map_or("streamed".to_string(), |urls| urls.to_string()),
+        config.concurrency
+    )?;
+    writeln!(
+        out,
+        "| Requests | {} ({:.1}/s mean, {}/s peak) |",
+        throughput.requests, throughput.requests_per_sec, throughput.peak_requests_per_sec
+    )?;
+    writeln!(
+        out,
+        "| Received | {} ({}/s mean) |",
+        format_bytes(throughput.bytes_received as f64),
+        format_bytes(throughput.received_per_sec)
+    )?;
+    writeln!(
+        out,
+        "| Sent | {} ({}/s mean) |",
+        format_bytes(throughput.bytes_sent as f64),
+        format_bytes(throughput.sent_per_sec)
+    )?;
+    writeln!(
+        out,
+        "| Errors | {:.2}% ({} request, {} response) |",
+        report.error_rate() * 100.0,
+        report.errors.request,
+        report.errors.response
+    )?;
+    if report.retries.retries > 0 {
+        writeln!(
+            out,
+            "| Retries | {} across {} requests, {} exhausted |",
+            report.retries.retries, report.retries.retried_requests, report.retries.exhausted
+        )?;
+    }
+
+    if !report.thresholds.is_empty() {
+        writeln!(out, "\n### Thresholds\n")?;
+        writeln!(out, "| result | threshold | actual |\n|---|---|---|")?;
+        for threshold in &report.thresholds {
+            let result = match threshold.passed {
+                true => "pass",
+                false => "**FAIL**",
+            };
+            writeln!(
+                out,
+                "| {} | {} | {} |",
+                result,
+                escape(&threshold.threshold),
+                threshold.actual
+            )?;
+        }
+    }
+
+    // Latency, overall then by the type of request
+    writeln!(out, "\n### Latency\n")?;
+    writeln!(
+        out,
+        "| | requests | 50% | 75% | 95% | 99% | 99.9% | max |\n|---|---|---|---|---|---|---|---|"
+    )?;
+    write_percentiles(out, "all", &report.latency)?;
+    for (class, latency) in &report.class_latency {
+        write_percentiles(out, class, latency)?;
+    }
+    let extra = [
+        ("setup", &report.setup_latency),
+        ("first attempt", &report.first_attempt_latency),
+        ("end to end", &report.end_to_end_latency),
+    ];
+    for (name, latency) in extra {
+        if let Some(latency) = latency {
+            write_percentiles(out, name, latency)?;
+        }
+    }
+
+    if !report.phase_latency.is_empty() {
+        writeln!(out, "\n### Phases\n")?;
+        writeln!(
+            out,
+            "| | requests | 50% | 75% | 95% | 99% | 99.9% | max |\n|---|---|---|---|---|---|---|---|"
+        )?;
+        for phase in &report.phase_latency {
+            let name = format!("{} ({})", phase.phase, phase.connection);
+            write_percentiles(out, &name, &phase.latency)?;
+        }
+    }
+
+    writeln!(out, "\n### HTTP responses\n")?;
+    writeln!(out, "| status | count |\n|---|---|")?;
+    for (status, count) in &report.status {
+        writeln!(out, "| {} | {} |", status, count)?;
+    }
+
+    let failures = [
+        ("Transport errors", "error", &report.errors.transport),
+        (
+            "Failed assertions",
+            "assertion",
+            &report.errors.failed_assertions,
+        ),
+    ];
+    for (title, column, counts) in failures {
+        if counts.is_empty() {
+            continue;
+        }
+        writeln!(out, "\n### {}\n", title)?;
+        writeln!(out, "| {} | count |\n|---|---|", column)?;
+        for (name, count) in counts {
+            writeln!(out, "| {} | {} |", escape(name), count)?;
+        }
+    }
+
+    if let Some(slow) = &report.slow_requests {
+        writeln!(
+            out,
+            "\n### Slow requests ({}%'ile -> {})\n",
+            slow.percentile,
+            format_micros(slow.threshold_us)
+        )?;
+        writeln!(
+            out,
+            "| max | avg | min | count | request |\n|---|---|---|---|---|"
+        )?;
+        for url in &slow.urls {
+            writeln!(
+                out,
+                "| {} | {} | {} | {} | {} |",
+                format_micros(url.max_us),
+                format_micros(url.avg_us),
+                format_micros(url.min_us),
+                url.count,
+                escape(&url.url)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn write_percentiles(
+    out: &mut dyn Write,
+    name: &str,
+    latency: &Percentiles,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(
+        out,
+        "| {} | {} | {} | {} | {} | {} | {} | {} |",
+        escape(name),
+        latency.count,
+        format_micros(latency.p50_us),
+        format_micros(latency.p75_us),
+        format_micros(latency.p95_us),
+        format_micros(latency.p99_us),
+        format_micros(latency.p99_9_us),
+        format_micros(latency.max_us)
+    )?;
+    Ok(())
+}
+
+// Pipes would end the table cell early
+fn escape(text: &str) -> String {
+    text.replace('|', "\\|")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::report::tests::run_config;
+    use crate::report::{SlowRequests, SlowUrl};
+    use crate::threshold::ThresholdResult;
+    use crate::workers::BenchResult;
+
+    // Verify the results are rendered as tables, with pipes in URLs escaped
+    #[test]
+    fn tables_rendered() {
+        let mut summary = BenchResult::new();
+        summary.status.insert(200, 3);
+        summary.latency += 2_000;
+        summary.run_time = Duration::from_secs(1);
+        let slow = SlowRequests {
+            percentile: 99.0,
+            threshold_us: 1_500,
+            urls: vec![SlowUrl {
+                url: "/search?q=a|b".to_string(),
+                count: 1,
+                min_us: 2_000,
+                avg_us: 2_000,
+                max_us: 2_000,
+            }],
+        };
+        let thresholds = vec![ThresholdResult {
+            threshold: "p99<1ms".to_string(),
+            actual: "2.00ms".to_string(),
+            passed: false,
+        }];
+        let report =
+            Report::new(run_config(), &summary, Some(slow), Vec::new(), thresholds).unwrap();
+
+        let mut markdown = Vec::new();
+        write(&report, &mut markdown).unwrap();
+        let markdown = String::from_utf8(markdown).unwrap();
+        assert!(markdown.starts_with("## hb results\n\n| | |\n|---|---|\n| Run time | 1.00s |\n"));
+        assert!(markdown.contains("| Load | 3 GET requests across 1 URLs from 2 workers |\n"));
+        assert!(markdown.contains("\n### Thresholds\n\n| result | threshold | actual |\n|---|---|---|\n| **FAIL** | p99<1ms | 2.00ms |\n"));
+        assert!(
+            markdown
+                .contains("\n### HTTP responses\n\n| status | count |\n|---|---|\n| 200 | 3 |\n")
+        );
+        assert!(markdown.contains(
+            "\n### Slow requests (99%'ile -> 1.50ms)\n\n| max | avg | min | count | request |\n|---|---|---|---|---|\n\
+             | 2.00ms | 2.00ms | 2.00ms | 1 | /search?q=a\\|b |\n"
+        ));
+        assert!(!markdown.contains("### Phases"));
+    }
+}
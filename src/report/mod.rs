@@ -0,0 +1,603 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::io::Write;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hdrhistogram::Histogram;
+use hdrhistogram::serialization::{Deserializer, Serializer, V2DeflateSerializer};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, OutputFormat};
+use crate::threshold::ThresholdResult;
+use crate::urlsource;
+use crate::urlsource::UrlSource;
+use crate::workers::timeline::TimelineRow;
+use crate::workers::{BenchResult, Transfer, latency_histogram};
+
+mod csv;
+mod html;
+mod markdown;
+
+/// The version of the report schema. It changes whenever a field is renamed, removed or changes meaning, but not when
+/// fields are added
+pub(crate) const SCHEMA_VERSION: u32 = 1;
+
+/// How many of the most requested URLs the report breaks latency down for
+pub(crate) const URL_LATENCY_LIMIT: usize = 100;
+
+/// The results of a run, in a form that can be written out for other tools (latency is in microseconds throughout)
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Report {
+    pub schema_version: u32,
+    pub hb_version: String,
+    pub config: RunConfig,
+    pub run_time_secs: f64,
+    pub status: BTreeMap<u16, u32>,
+    pub errors: Errors,
+    pub retries: Retries,
+    pub throughput: Throughput,
+    // Requests that got a response
+    pub latency: Percentiles,
+    pub latency_distribution: Vec<DistributionPoint>,
+    // The whole histogram in the HdrHistogram V2 format (base64), so runs can be compared statistically
+    #[serde(default)]
+    pub latency_histogram: Option<String>,
+    // By class of response e.g. 2xx or timeout
+    pub class_latency: BTreeMap<String, Percentiles>,
+    pub phase_latency: Vec<PhaseLatency>,
+    pub setup_latency: Option<Percentiles>,
+    pub first_attempt_latency: Option<Percentiles>,
+    pub end_to_end_latency: Option<Percentiles>,
+    pub timeline: Vec<TimelineRow>,
+    pub slow_requests: Option<SlowRequests>,
+    #[serde(default)]
+    pub url_latency: Vec<UrlLatency>,
+    #[serde(default)]
+    pub thresholds: Vec<ThresholdResult>,
+}
+
+/// How the test was run. Secrets such as header values and credentials are left out
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct RunConfig {
+    pub concurrency: u16,
+    pub requests: usize,
+    // Unknown when streaming URLs from a file
+    pub urls: Option<usize>,
+    pub method: String,
+    pub order: String,
+    pub delay_ms: u32,
+    pub delay_distribution: String,
+    pub interval_secs: u64,
+    pub connect_timeout_ms: Option<u64>,
+    pub first_byte_timeout_ms: Option<u64>,
+    pub read_timeout_ms: Option<u64>,
+    pub timeout_ms: Option<u64>,
+    pub max_retries: u32,
+    pub assertions: Vec<String>,
+    pub discard_body: bool,
+    pub slow_percentile: Option<f64>,
+}
+
+/// Requests that failed, by cause
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Errors {
+    // Requests without a 1xx, 2xx or 3xx response, and their share of all requests. These are the figures checked by
+    // the thresholds and compared between runs (and missing from reports written before they were added)
+    #[serde(default)]
+    pub counted: Option<u64>,
+    #[serde(default)]
+    pub rate: Option<f64>,
+    pub request: u32,
+    pub response: u32,
+    pub setup: u32,
+    pub assertion_failures: u32,
+    pub transport: BTreeMap<String, u32>,
+    pub failed_assertions: BTreeMap<String, u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Retries {
+    pub retries: u64,
+    pub retried_requests: u32,
+    // Requests that still failed after the last retry
+    pub exhausted: u32,
+}
+
+/// The request rate and bytes transferred, as a mean over the run and the peak in any one second
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Throughput {
+    pub requests: u64,
+    pub requests_per_sec: f64,
+    pub peak_requests_per_sec: u64,
+    pub bytes_received: u64,
+    pub received_per_sec: f64,
+    pub peak_received_per_sec: u64,
+    pub bytes_sent: u64,
+    pub sent_per_sec: f64,
+    pub peak_sent_per_sec: u64,
+}
+
+/// A summary of a latency histogram
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) struct Percentiles {
+    pub count: u64,
+    pub min_us: u64,
+    pub mean_us: f64,
+    pub p50_us: u64,
+    pub p75_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+    pub p99_9_us: u64,
+    pub p99_99_us: u64,
+    pub p99_999_us: u64,
+    pub max_us: u64,
+}
+
+/// A point on the latency distribution: the given percentage of requests took at most this long
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) struct DistributionPoint {
+    pub percentile: f64,
+    pub latency_us: u64,
+}
+
+/// The time spent in one phase of the requests on new or reused connections
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PhaseLatency {
+    pub connection: String,
+    pub phase: String,
+    pub latency: Percentiles,
+}
+
+/// The URLs with requests slower than the given percentile of all requests, slowest first
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SlowRequests {
+    pub percentile: f64,
+    pub threshold_us: u64,
+    pub urls: Vec<SlowUrl>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SlowUrl {
+    pub url: String,
+    pub count: usize,
+    pub min_us: u64,
+    pub avg_us: u64,
+    pub max_us: u64,
+}
+
+/// The latency of one of the most requested URLs, including attempts that didn't get a response
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct UrlLatency {
+    pub url: String,
+    pub latency: Percentiles,
+    pub histogram: String,
+}
+
+impl Report {
+    pub(crate) fn new(
+        config: RunConfig,
+        summary: &BenchResult,
+        slow_requests: Option<SlowRequests>,
+        url_latency: Vec<UrlLatency>,
+        thresholds: Vec<ThresholdResult>,
+    ) -> Result<Report, Box<dyn Error>> {
+        let optional = |latency: &Histogram<u64>| match latency.is_empty() {
+            true => None,
+            false => Some(Percentiles::new(latency)),
+        };
+
+        Ok(Report {
+            schema_version: SCHEMA_VERSION,
+            hb_version: env!("CARGO_PKG_VERSION").to_string(),
+            config,
+            run_time_secs: summary.run_time.as_secs_f64(),
+            status: summary.status.iter().map(|(k, v)| (*k, *v)).collect(),
+            errors: Errors {
+                counted: Some(summary.tally.errors),
+                rate: Some(summary.tally.error_rate()),
+                request: summary.request_errors,
+                response: summary.response_errors,
+                setup: summary.setup_errors,
+                assertion_failures: summary.assertion_failures,
+                transport: by_name(&summary.transport_errors),
+                failed_assertions: by_name(&summary.failed_assertions),
+            },
+            retries: Retries {
+                retries: summary.retries,
+                retried_requests: summary.retried_requests,
+                exhausted: summary.retries_exhausted,
+            },
+            throughput: Throughput::new(summary.run_time, summary),
+            latency: Percentiles::new(&summary.latency),
+            latency_distribution: distribution(&summary.latency),
+            latency_histogram: Some(encode_histogram(&summary.latency)?),
+            class_latency: summary
+                .class_latency
+                .iter()
+                .map(|(class, latency)| (class.to_string(), Percentiles::new(latency)))
+                .collect(),
+            phase_latency: summary
+                .phase_latency
+                .iter()
+                .map(|((connection, phase), latency)| PhaseLatency {
+                    connection: connection.name().to_string(),
+                    phase: phase.to_string(),
+                    latency: Percentiles::new(latency),
+                })
+                .collect(),
+            setup_latency: optional(&summary.setup_latency),
+            first_attempt_latency: optional(&summary.first_attempt_latency),
+            end_to_end_latency: optional(&summary.end_to_end_latency),
+            timeline: summary.timeline.rows(),
+            slow_requests,
+            url_latency,
+            thresholds,
+        })
+    }
+
+    /// The requests counted as errors and all the requests. Older reports only have them in the timeline, which
+    /// counts errors the same way
+    pub(crate) fn error_counts(&self) -> (u64, u64) {
+        match self.errors.counted {
+            Some(errors) => (errors, self.throughput.requests),
+            None => self
+                .timeline
+                .iter()
+                .fold((0, 0), |(errors, requests), row| {
+                    (errors + row.errors, requests + row.requests)
+                }),
+        }
+    }
+
+    /// The share of requests that were errors
+    pub(crate) fn error_rate(&self) -> f64 {
+        self.errors.rate.unwrap_or_else(|| {
+            let (errors, requests) = self.error_counts();
+            errors as f64 / requests.max(1) as f64
+        })
+    }
+
+    /// Writes the report in the given format
+    pub(crate) fn write(
+        &self,
+        format: OutputFormat,
+        out: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        match format {
+            OutputFormat::Json => {
+                serde_json::to_writer_pretty(&mut *out, self)?;
+                writeln!(out)?;
+            }
+            OutputFormat::Csv => csv::write(self, out)?,
+            OutputFormat::Markdown => markdown::write(self, out)?,
+            OutputFormat::Html => html::write(self, out)?,
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+// The latency at increasingly fine steps towards the tail, as HdrHistogram tools plot it
+fn distribution(latency: &Histogram<u64>) -> Vec<DistributionPoint> {
+    latency
+        .iter_quantiles(5)
+        .map(|value| DistributionPoint {
+            percentile: value.quantile_iterated_to() * 100.0,
+            latency_us: value.value_iterated_to(),
+        })
+        .collect()
+}
+
+/// Encodes a histogram in the compressed HdrHistogram V2 format as base64, as other HdrHistogram tools read it
+pub(crate) fn encode_histogram(latency: &Histogram<u64>) -> Result<String, Box<dyn Error>> {
+    let mut bytes = Vec::new();
+    V2DeflateSerializer::new().serialize(latency, &mut bytes)?;
+    Ok(STANDARD.encode(bytes))
+}
+
+pub(crate) fn decode_histogram(encoded: &str) -> Result<Histogram<u64>, Box<dyn Error>> {
+    let bytes = STANDARD.decode(encoded)?;
+    Ok(Deserializer::new().deserialize(&mut bytes.as_slice())?)
+}
+
+// Counts keyed by their display name, so they sort and serialize consistently
+fn by_name<K: ToString>(counts: &HashMap<K, u32>) -> BTreeMap<String, u32> {
+    counts.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+}
+
+impl RunConfig {
+    /// Captures the config before it's handed over to the workers
+    pub(crate) fn new(config: &Config, urls: Option<usize>) -> RunConfig {
+        let millis = |timeout: Option<Duration>| timeout.map(|t| t.as_millis() as u64);
+        RunConfig {
+            concurrency: config.concurrency,
+            requests: config.requests,
+            urls,
+            method: config.http_method.as_str().to_string(),
+            order: config.order.as_str().to_string(),
+            delay_ms: config.delay_ms,
+            delay_distribution: config.delay_distrib.as_str().to_string(),
+            interval_secs: config.interval.as_secs(),
+            connect_timeout_ms: millis(config.timeouts.connect),
+            first_byte_timeout_ms: millis(config.timeouts.first_byte),
+            read_timeout_ms: millis(config.timeouts.body),
+            timeout_ms: millis(config.timeouts.overall),
+            max_retries: config.retry.as_ref().map_or(0, |retry| retry.max_retries),
+            assertions: config.assertions.iter().map(ToString::to_string).collect(),
+            discard_body: config.discard_body,
+            slow_percentile: config.slow_percentile,
+        }
+    }
+}
+
+impl Throughput {
+    pub(crate) fn new(run_time: Duration, summary: &BenchResult) -> Throughput {
+        let secs = run_time.as_secs_f64().max(f64::EPSILON);
+        let requests = summary.per_second.iter().map(|t| t.requests).sum::<u64>();
+        let peak = |f: fn(&Transfer) -> u64| summary.per_second.iter().map(f).max().unwrap_or(0);
+        Throughput {
+            requests,
+            requests_per_sec: requests as f64 / secs,
+            peak_requests_per_sec: peak(|t| t.requests),
+            bytes_received: summary.bytes_received,
+            received_per_sec: summary.bytes_received as f64 / secs,
+            peak_received_per_sec: peak(|t| t.bytes_received),
+            bytes_sent: summary.bytes_sent,
+            sent_per_sec: summary.bytes_sent as f64 / secs,
+            peak_sent_per_sec: peak(|t| t.bytes_sent),
+        }
+    }
+}
+
+impl Percentiles {
+    pub(crate) fn new(latency: &Histogram<u64>) -> Percentiles {
+        Percentiles {
+            count: latency.len(),
+            min_us: latency.min(),
+            mean_us: latency.mean(),
+            p50_us: latency.value_at_percentile(50.0),
+            p75_us: latency.value_at_percentile(75.0),
+            p95_us: latency.value_at_percentile(95.0),
+            p99_us: latency.value_at_percentile(99.0),
+            p99_9_us: latency.value_at_percentile(99.9),
+            p99_99_us: latency.value_at_percentile(99.99),
+            p99_999_us: latency.value_at_percentile(99.999),
+            max_us: latency.max(),
+        }
+    }
+}
+
+impl UrlLatency {
+    /// The latency of the URLs requested most often, most requested first
+    pub(crate) fn most_requested(
+        summary: &BenchResult,
+        urls: &UrlSource,
+        limit: usize,
+    ) -> Result<Vec<UrlLatency>, Box<dyn Error>> {
+        // The same URL may appear more than once in the URL file, so group by the URL itself
+        let url_indexes = summary
+            .request_times
+            .iter()
+            .map(|(url_index, _)| *url_index)
+            .collect::<HashSet<usize>>();
+        let url_lookup = urls.lookup(&url_indexes)?;
+        let mut url_histograms = HashMap::new();
+        for (url_index, duration) in &summary.request_times {
+            let url = url_lookup
+                .get(url_index)
+                .map_or("?", |line| urlsource::split_line(line).0);
+            url_histograms
+                .entry(url)
+                .or_insert_with(latency_histogram)
+                .saturating_record(*duration);
+        }
+
+        let mut url_histograms = url_histograms.into_iter().collect::<Vec<_>>();
+        url_histograms.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(b.0)));
+        url_histograms
+            .into_iter()
+            .take(limit)
+            .map(|(url, latency)| {
+                Ok(UrlLatency {
+                    url: url.to_string(),
+                    latency: Percentiles::new(&latency),
+                    histogram: encode_histogram(&latency)?,
+                })
+            })
+            .collect()
+    }
+}
+
+impl SlowRequests {
+    /// Finds the URLs with requests slower than the given percentile of all requests
+    pub(crate) fn new(
+        summary: &BenchResult,
+        urls: &UrlSource,
+        percentile: f64,
+    ) -> Result<SlowRequests, Box<dyn Error>> {
+        // Resolve the URLs we requested (which may need to be read back from the URL file)
+        let url_indexes = summary
+            .request_times
+            .iter()
+            .map(|(url_index, _)| *url_index)
+            .collect::<HashSet<usize>>();
+        let url_lookup = urls.lookup(&url_indexes)?;
+
+        // Collect all the durations by URL
+        let mut url_stats = HashMap::new();
+        for (url_index, duration) in &summary.request_times {
+            let url = url_lookup
+                .get(url_index)
+                .map_or("?", |line| urlsource::split_line(line).0);
+            url_stats
+                .entry(url)
+                .or_insert_with(Vec::new)
+                .push(*duration);
+        }
+
+        // Determine the lower latency bound for the request to be included in the slow requests report
+        let threshold_us = summary.latency.value_at_percentile(percentile);
+
+        // Compute the summary stats by URL and filter to those that exceed the smallest latency cutoff
+        let mut slow_urls = url_stats
+            .iter()
+            .filter_map(|(url, durations)| {
+                // Compute the basic stats we need for the report line
+                let (min, max, sum) =
+                    durations.iter().fold((u64::MAX, 0, 0), |state, &duration| {
+                        (
+                            state.0.min(duration),
+                            state.1.max(duration),
+                            state.2 + duration,
+                        )
+                    });
+
+                // If the max latency didn't exceed our lower bound then we just ignore this URL for the report
+                if max < threshold_us {
+                    return None;
+                }
+
+                let count = durations.len();
+                Some(SlowUrl {
+                    url: url.to_string(),
+                    count,
+                    min_us: min,
+                    avg_us: sum / count as u64,
+                    max_us: max,
+                })
+            })
+            .collect::<Vec<SlowUrl>>();
+
+        // Sort by latency in descending order
+        slow_urls.sort_by_key(|slow_url| std::cmp::Reverse(slow_url.max_us));
+
+        Ok(SlowRequests {
+            percentile,
+            threshold_us,
+            urls: slow_urls,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workers::OutcomeClass;
+    use crate::workers::transport::TransportError;
+
+    pub(super) fn run_config() -> RunConfig {
+        RunConfig {
+            concurrency: 2,
+            requests: 3,
+            urls: Some(1),
+            method: "GET".to_string(),
+            order: "random".to_string(),
+            delay_ms: 0,
+            delay_distribution: "constant".to_string(),
+            interval_secs: 1,
+            connect_timeout_ms: None,
+            first_byte_timeout_ms: None,
+            read_timeout_ms: None,
+            timeout_ms: Some(500),
+            max_retries: 0,
+            assertions: Vec::new(),
+            discard_body: false,
+            slow_percentile: None,
+        }
+    }
+
+    // Verify the report carries the results, and reads back from its JSON form
+    #[test]
+    fn report_round_trip() {
+        let mut summary = BenchResult::new();
+        summary.status.insert(200, 2);
+        summary
+            .transport_errors
+            .insert(TransportError::ConnectFailed, 1);
+        summary.latency += 1_000;
+        summary.latency += 3_000;
+        summary.run_time = Duration::from_secs(2);
+
+        let report = Report::new(run_config(), &summary, None, Vec::new(), Vec::new()).unwrap();
+        let mut json = Vec::new();
+        report.write(OutputFormat::Json, &mut json).unwrap();
+        let report: Report = serde_json::from_slice(&json).unwrap();
+
+        assert_eq!(SCHEMA_VERSION, report.schema_version);
+        assert_eq!(Some(&2), report.status.get(&200));
+        assert_eq!(Some(&1), report.errors.transport.get("connect failed"));
+        assert_eq!(2, report.latency.count);
+        assert_eq!(summary.latency.max(), report.latency.max_us);
+        assert_eq!(Some(500), report.config.timeout_ms);
+        assert!(report.setup_latency.is_none());
+        let latency = decode_histogram(report.latency_histogram.as_ref().unwrap()).unwrap();
+        assert_eq!(summary.latency, latency);
+
+        // The other formats include the same figures
+        let mut csv = Vec::new();
+        report.write(OutputFormat::Csv, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("section,name,metric,value\n"));
+        assert!(csv.contains("\nstatus,200,count,2\n"));
+        assert!(csv.contains(&format!("\nlatency,all,max_us,{}\n", summary.latency.max())));
+
+        let mut markdown = Vec::new();
+        report.write(OutputFormat::Markdown, &mut markdown).unwrap();
+        let markdown = String::from_utf8(markdown).unwrap();
+        assert!(markdown.contains("| 200 | 2 |"));
+        assert!(markdown.contains("| connect failed | 1 |"));
+
+        let mut html = Vec::new();
+        report.write(OutputFormat::Html, &mut html).unwrap();
+        let html = String::from_utf8(html).unwrap();
+        assert!(!html.contains("{{report}}"));
+        assert!(html.contains("\"schema_version\":1"));
+    }
+
+    // Verify the report's error rate is the one the thresholds check, and can be worked out for older reports
+    #[test]
+    fn error_rate_counted() {
+        let mut summary = BenchResult::new();
+        for class in [
+            OutcomeClass::Success,
+            OutcomeClass::Redirect,
+            OutcomeClass::ServerError,
+            OutcomeClass::Timeout,
+        ] {
+            summary.tally.record(class, 1_000);
+        }
+        summary.request_errors = 1;
+        summary.per_second = vec![Transfer {
+            requests: 4,
+            ..Transfer::default()
+        }];
+
+        let mut report = Report::new(run_config(), &summary, None, Vec::new(), Vec::new()).unwrap();
+        assert_eq!(Some(0.5), report.errors.rate);
+        assert_eq!((2, 4), report.error_counts());
+
+        let mut markdown = Vec::new();
+        report.write(OutputFormat::Markdown, &mut markdown).unwrap();
+        assert!(
+            String::from_utf8(markdown)
+                .unwrap()
+                .contains("| Errors | 50.00% (1 request, 0 response) |")
+        );
+
+        report.errors.counted = None;
+        report.errors.rate = None;
+        report.timeline = vec![TimelineRow {
+            start_secs: 0.0,
+            requests: 8,
+            errors: 2,
+            p50_us: 1_000,
+            p90_us: 1_000,
+            p99_us: 1_000,
+            max_us: 1_000,
+        }];
+        assert_eq!((2, 8), report.error_counts());
+        assert_eq!(0.25, report.error_rate());
+    }
+}
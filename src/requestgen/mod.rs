@@ -1,7 +1,8 @@
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 
 use crate::config;
 
@@ -13,6 +14,10 @@ pub(crate) struct RequestGenerator {
     url_index_supplier: Box<dyn indexseq::IndexSupplier>,
     time_delay_supplier: Box<dyn timedelay::TimeDelaySupplier>,
     pub(crate) progress: Mutex<ProgressBar>,
+    // Whether the live dashboard is shown in place of the progress bar
+    pub(crate) dashboard: bool,
+    // Set to stop handing out requests before they've all been generated
+    stopped: AtomicBool,
 }
 
 impl RequestGenerator {
@@ -27,8 +32,14 @@ impl RequestGenerator {
         let time_delay_supplier =
             timedelay::create_supplier(&config.delay_ms, &config.delay_distrib);
 
-        // Initialise the request generator
-        let progress = ProgressBar::new(num_requests as u64);
+        // Initialise the request generator. The progress is still tracked when the dashboard is shown, but not drawn
+        let progress = match config.dashboard {
+            true => ProgressBar::with_draw_target(
+                Some(num_requests as u64),
+                ProgressDrawTarget::hidden(),
+            ),
+            false => ProgressBar::new(num_requests as u64),
+        };
         progress.set_style(
             ProgressStyle::default_bar()
                 .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
@@ -40,11 +51,21 @@ impl RequestGenerator {
             url_index_supplier,
             time_delay_supplier,
             progress: Mutex::new(progress),
+            dashboard: config.dashboard,
+            stopped: AtomicBool::new(false),
         }
     }
 
+    /// Stops generating requests, so the test ends once the requests in flight complete
+    pub(crate) fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
     /// Return the next request to execute or None if no more requests need to be executed
     pub(crate) fn next(&self) -> Option<Request> {
+        if self.stopped.load(Ordering::Relaxed) {
+            return None;
+        }
         self.url_index_supplier.next_index().map(move |url_index| {
             // Determine the time delay for this request
             let sleep = self.time_delay_supplier.next_delay();
@@ -73,7 +94,8 @@ unsafe impl Sync for RequestGenerator {}
 
 #[cfg(test)]
 mod tests {
-    use crate::config::{DelayDistribution, HttpMethod, RequestOrder};
+    use crate::compare::Tolerances;
+    use crate::config::{DelayDistribution, HttpMethod, RequestOrder, Timeouts};
 
     use super::*;
 
@@ -88,10 +110,28 @@ mod tests {
             delay_distrib: DelayDistribution::Constant,
             slow_percentile: None,
             http_method: HttpMethod::Get,
-            headers: None,
+            headers: Vec::new(),
+            auth: None,
+            signer: None,
+            session: None,
+            setup: None,
+            assertions: Vec::new(),
+            timeouts: Timeouts::default(),
+            retry: None,
+            discard_body: false,
+            interval: Duration::from_secs(1),
+            timeline_file: None,
+            hlog_file: None,
+            dashboard: false,
+            output: None,
+            output_file: None,
+            thresholds: Vec::new(),
+            abort_on_threshold: false,
+            baseline: None,
+            tolerances: Tolerances::default(),
         };
 
-        let urls = vec![
+        let urls = [
             "http://one".to_string(),
             "http://two".to_string(),
             "http://three".to_string(),
@@ -102,5 +142,11 @@ mod tests {
         assert_eq!(1, generator.next().unwrap().url_index);
         assert_eq!(2, generator.next().unwrap().url_index);
         assert_eq!(None, generator.next());
+
+        // Verify a stopped generator doesn't hand out any more requests
+        let generator = RequestGenerator::new(&config, urls.len());
+        assert_eq!(0, generator.next().unwrap().url_index);
+        generator.stop();
+        assert_eq!(None, generator.next());
     }
 }
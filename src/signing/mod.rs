@@ -0,0 +1,103 @@
+use std::error::Error;
+use std::time::SystemTime;
+
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::headers::RequestHeader;
+
+pub(crate) mod hmac_header;
+pub(crate) mod sigv4;
+
+/// The parts of a request covered by a signature
+pub(crate) struct SigningRequest<'a> {
+    pub method: &'a str,
+    pub url: &'a Url,
+    pub headers: &'a [RequestHeader<'a>],
+    pub body: &'a [u8],
+}
+
+/// Computes the headers that sign each request as it is sent
+pub(crate) trait Signer: Send + Sync {
+    fn sign(
+        &self,
+        request: &SigningRequest,
+        now: SystemTime,
+    ) -> Result<Vec<(String, String)>, Box<dyn Error>>;
+}
+
+// HMAC-SHA256 of the data with the given key
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Hex encoded SHA256 of the data
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+// The host header value sent with the request: any given explicitly, otherwise the host of the URL
+fn request_host(request: &SigningRequest) -> String {
+    request
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("host"))
+        .map_or_else(|| host(request.url), |(_, value)| value.trim().to_string())
+}
+
+// The host header value for the URL, including the port if it isn't the default for the scheme
+fn host(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    }
+}
+
+// Converts seconds since the UNIX epoch to a UTC (year, month, day, hour, minute, second)
+fn utc_datetime(now: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400) as u32);
+
+    // Civil date from days since the epoch (http://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    // Verify we convert timestamps to UTC dates correctly (including leap years)
+    #[test]
+    fn utc_dates() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!((1970, 1, 1, 0, 0, 0), utc_datetime(at(0)));
+        assert_eq!((2015, 8, 30, 12, 36, 0), utc_datetime(at(1440938160)));
+        assert_eq!((2024, 2, 29, 23, 59, 59), utc_datetime(at(1709251199)));
+    }
+}
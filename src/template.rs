@@ -0,0 +1,195 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A string containing {{placeholders}} that are substituted as each request is sent
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// The values that can be substituted into a template
+#[derive(Clone, Debug, PartialEq)]
+enum Placeholder {
+    // The worker sending the request
+    Worker,
+    // The number of requests sent by this worker so far
+    Sequence,
+    // A random unsigned integer
+    Random,
+    // A random (version 4) UUID
+    Uuid,
+    // Milliseconds since the UNIX epoch
+    Timestamp,
+    // A value captured by this worker during the setup phase
+    Variable(String),
+}
+
+/// The per-request values available to templates
+pub(crate) struct RequestContext<'a> {
+    pub worker_id: u16,
+    pub sequence: u64,
+    pub variables: &'a HashMap<String, String>,
+}
+
+impl Template {
+    /// Parses the placeholders in the string, which may refer to the nominated variables. Anything else in braces
+    /// isn't a placeholder, so is sent as it is
+    pub(crate) fn parse(s: &str, variables: &HashSet<String>) -> Template {
+        let mut segments = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find("{{") {
+            let Some(len) = rest[start + 2..].find("}}") else {
+                break;
+            };
+            let end = start + 2 + len + 2;
+            let name = rest[start + 2..start + 2 + len].trim();
+            let placeholder = match name {
+                "worker" => Placeholder::Worker,
+                "sequence" => Placeholder::Sequence,
+                "random" => Placeholder::Random,
+                "uuid" => Placeholder::Uuid,
+                "timestamp" => Placeholder::Timestamp,
+                _ if variables.contains(name) => Placeholder::Variable(name.to_string()),
+                _ => {
+                    push_literal(&mut segments, &rest[..end]);
+                    rest = &rest[end..];
+                    continue;
+                }
+            };
+            push_literal(&mut segments, &rest[..start]);
+            segments.push(Segment::Placeholder(placeholder));
+            rest = &rest[end..];
+        }
+        push_literal(&mut segments, rest);
+
+        Template { segments }
+    }
+
+    /// Checks that every variable the template refers to has a value
+    pub(crate) fn is_resolved(&self, variables: &HashMap<String, String>) -> bool {
+        self.segments.iter().all(|segment| match segment {
+            Segment::Placeholder(Placeholder::Variable(name)) => variables.contains_key(name),
+            _ => true,
+        })
+    }
+
+    /// Substitutes the placeholders for this request
+    pub(crate) fn render(&self, context: &RequestContext) -> Cow<'_, str> {
+        match self.segments.as_slice() {
+            [] => Cow::Borrowed(""),
+            [Segment::Literal(literal)] => Cow::Borrowed(literal),
+            segments => {
+                let mut rendered = String::new();
+                for segment in segments {
+                    match segment {
+                        Segment::Literal(literal) => rendered.push_str(literal),
+                        Segment::Placeholder(placeholder) => {
+                            rendered.push_str(&placeholder.value(context))
+                        }
+                    }
+                }
+                Cow::Owned(rendered)
+            }
+        }
+    }
+}
+
+// Appends the text, joining it to any literal before it so plain text stays a single segment
+fn push_literal(segments: &mut Vec<Segment>, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    match segments.last_mut() {
+        Some(Segment::Literal(literal)) => literal.push_str(text),
+        _ => segments.push(Segment::Literal(text.to_string())),
+    }
+}
+
+impl Placeholder {
+    fn value(&self, context: &RequestContext) -> String {
+        match self {
+            Placeholder::Variable(name) => context.variables.get(name).cloned().unwrap_or_default(),
+            Placeholder::Worker => context.worker_id.to_string(),
+            Placeholder::Sequence => context.sequence.to_string(),
+            Placeholder::Random => rand::random::<u32>().to_string(),
+            Placeholder::Uuid => {
+                let mut bytes: [u8; 16] = rand::random();
+                bytes[6] = (bytes[6] & 0x0f) | 0x40;
+                bytes[8] = (bytes[8] & 0x3f) | 0x80;
+                let hex = bytes
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>();
+                format!(
+                    "{}-{}-{}-{}-{}",
+                    &hex[0..8],
+                    &hex[8..12],
+                    &hex[12..16],
+                    &hex[16..20],
+                    &hex[20..32]
+                )
+            }
+            Placeholder::Timestamp => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify placeholders are substituted and literals are passed through untouched
+    #[test]
+    fn render_placeholders() {
+        let variables = HashMap::from([("token".to_string(), "abc".to_string())]);
+        let context = RequestContext {
+            worker_id: 3,
+            sequence: 42,
+            variables: &variables,
+        };
+        let template = Template::parse("w{{worker}}-{{ sequence }}-{{}", &HashSet::new());
+        assert_eq!("w3-42-{{}", template.render(&context));
+
+        let template = Template::parse("Bearer abc", &HashSet::new());
+        assert!(matches!(
+            template.render(&context),
+            Cow::Borrowed("Bearer abc")
+        ));
+
+        let uuid = Template::parse("{{uuid}}", &HashSet::new())
+            .render(&context)
+            .into_owned();
+        assert_eq!(36, uuid.len());
+        assert_eq!(Some('4'), uuid.chars().nth(14));
+    }
+
+    // Verify text in braces that isn't a known placeholder is sent as it is
+    #[test]
+    fn unknown_placeholder() {
+        let variables = HashMap::new();
+        let context = RequestContext {
+            worker_id: 1,
+            sequence: 0,
+            variables: &variables,
+        };
+        let template = Template::parse("{{nope}} {{worker}} {{ {{x}}", &HashSet::new());
+        assert_eq!("{{nope}} 1 {{ {{x}}", template.render(&context));
+
+        let template = Template::parse("{\"a\": {{b}}}", &HashSet::new());
+        assert!(matches!(
+            template.render(&context),
+            Cow::Borrowed("{\"a\": {{b}}}")
+        ));
+    }
+}
@@ -0,0 +1,463 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::report::Throughput;
+use crate::requestgen::RequestGenerator;
+use crate::units::format_micros;
+use crate::workers::{BenchResult, RunState, Tally};
+
+// How often the monitor checks whether a threshold has been breached
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A limit on one of the results of the run e.g. p99<300ms, used to pass or fail the run
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Threshold {
+    metric: Metric,
+    comparison: Comparison,
+    limit: f64,
+}
+
+// What's being limited. Latency is in microseconds and the error rate a fraction
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Metric {
+    Percentile(f64),
+    Max,
+    Mean,
+    ErrorRate,
+    Errors,
+    RequestsPerSec,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// How a threshold fared against the results of a run
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ThresholdResult {
+    pub threshold: String,
+    pub actual: String,
+    pub passed: bool,
+}
+
+/// What the monitor knows about the run so far
+struct Progress<'a> {
+    elapsed: Option<Duration>,
+    // The most requests the run could make, if it runs to completion
+    max_requests: u64,
+    tally: &'a Tally,
+}
+
+impl Threshold {
+    /// Parses a threshold such as p99<300ms, max<=2s, mean<50ms, error_rate<0.5%, errors<10 or rps>1000
+    pub(crate) fn parse(threshold: &str) -> Result<Threshold, Box<dyn Error>> {
+        let invalid = || -> Box<dyn Error> {
+            format!(
+                "Invalid threshold '{}': expected a metric (pNN, max, mean, error_rate, errors or rps), \
+                 a comparison (<, <=, > or >=) and a limit e.g. p99<300ms",
+                threshold
+            )
+            .into()
+        };
+
+        let position = threshold.find(['<', '>']).ok_or_else(invalid)?;
+        let (metric, rest) = threshold.split_at(position);
+        let (comparison, limit) = match rest.as_bytes() {
+            [b'<', b'=', ..] => (Comparison::LessOrEqual, &rest[2..]),
+            [b'>', b'=', ..] => (Comparison::GreaterOrEqual, &rest[2..]),
+            [b'<', ..] => (Comparison::Less, &rest[1..]),
+            _ => (Comparison::Greater, &rest[1..]),
+        };
+
+        let metric = match metric.trim() {
+            "max" => Metric::Max,
+            "mean" => Metric::Mean,
+            "error_rate" => Metric::ErrorRate,
+            "errors" => Metric::Errors,
+            "rps" => Metric::RequestsPerSec,
+            percentile => {
+                let percentile = percentile
+                    .strip_prefix('p')
+                    .and_then(|p| p.parse::<f64>().ok())
+                    .filter(|p| (0.0..=100.0).contains(p))
+                    .ok_or_else(invalid)?;
+                Metric::Percentile(percentile)
+            }
+        };
+
+        let limit = limit.trim();
+        let limit = match metric {
+            Metric::Percentile(_) | Metric::Max | Metric::Mean => parse_latency(limit),
+            Metric::ErrorRate => match limit.strip_suffix('%') {
+                Some(percent) => percent.trim().parse::<f64>().ok().map(|p| p / 100.0),
+                None => limit.parse::<f64>().ok(),
+            },
+            Metric::Errors | Metric::RequestsPerSec => limit.parse::<f64>().ok(),
+        }
+        .ok_or_else(invalid)?;
+
+        Ok(Threshold {
+            metric,
+            comparison,
+            limit,
+        })
+    }
+
+    /// Checks the threshold against the results of the run
+    pub(crate) fn evaluate(&self, summary: &BenchResult) -> ThresholdResult {
+        let actual = self.actual(summary);
+        ThresholdResult {
+            threshold: self.to_string(),
+            actual: self.metric.format(actual),
+            passed: self.comparison.holds(actual, self.limit),
+        }
+    }
+
+    fn actual(&self, summary: &BenchResult) -> f64 {
+        let tally = &summary.tally;
+        match self.metric {
+            Metric::Percentile(percentile) => tally.latency.value_at_percentile(percentile) as f64,
+            Metric::Max => tally.latency.max() as f64,
+            Metric::Mean => tally.latency.mean(),
+            Metric::ErrorRate => tally.error_rate(),
+            Metric::Errors => tally.errors as f64,
+            Metric::RequestsPerSec => Throughput::new(summary.run_time, summary).requests_per_sec,
+        }
+    }
+
+    // Whether the threshold will fail however well the rest of the run goes. For example, once more than 1% of the
+    // most requests the run could make have been slower than the limit, the p99 can't be under it
+    fn breached(&self, progress: &Progress) -> bool {
+        let max_requests = progress.max_requests.max(1) as f64;
+        let tally = progress.tally;
+        let latency = &tally.latency;
+
+        // The best the final value could be, for the thresholds where that's known
+        let best = match (self.metric, self.comparison.is_upper_bound()) {
+            (Metric::Percentile(percentile), true) => {
+                // The bucket holding the limit may also hold values under it, so only values above that bucket count
+                let failing = latency
+                    .count_between(latency.highest_equivalent(self.limit as u64) + 1, u64::MAX);
+                let allowed = (1.0 - percentile / 100.0) * max_requests + 1.0;
+                return failing as f64 > allowed;
+            }
+            (Metric::Max, true) => latency.max() as f64,
+            (Metric::Mean, true) => latency.mean() * latency.len() as f64 / max_requests,
+            (Metric::ErrorRate, true) => tally.errors as f64 / max_requests,
+            (Metric::Errors, true) => tally.errors as f64,
+            (Metric::RequestsPerSec, false) => match progress.elapsed {
+                Some(elapsed) => max_requests / elapsed.as_secs_f64(),
+                None => return false,
+            },
+            _ => return false,
+        };
+        tally.requests > 0 && !self.comparison.holds(best, self.limit)
+    }
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let metric = match self.metric {
+            Metric::Percentile(percentile) => format!("p{}", percentile),
+            Metric::Max => "max".to_string(),
+            Metric::Mean => "mean".to_string(),
+            Metric::ErrorRate => "error_rate".to_string(),
+            Metric::Errors => "errors".to_string(),
+            Metric::RequestsPerSec => "rps".to_string(),
+        };
+        let comparison = match self.comparison {
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        };
+        write!(
+            f,
+            "{}{}{}",
+            metric,
+            comparison,
+            self.metric.format(self.limit)
+        )
+    }
+}
+
+impl Metric {
+    fn format(&self, value: f64) -> String {
+        match self {
+            Metric::Percentile(_) | Metric::Max | Metric::Mean => {
+                format_micros(value.round() as u64)
+            }
+            Metric::ErrorRate => format!("{}%", round(value * 100.0)),
+            Metric::Errors => format!("{}", value),
+            Metric::RequestsPerSec => round(value),
+        }
+    }
+}
+
+// Rounds for display, without trailing zeros
+fn round(value: f64) -> String {
+    let rounded = format!("{:.2}", value);
+    rounded
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+impl Comparison {
+    fn holds(&self, actual: f64, limit: f64) -> bool {
+        match self {
+            Comparison::Less => actual < limit,
+            Comparison::LessOrEqual => actual <= limit,
+            Comparison::Greater => actual > limit,
+            Comparison::GreaterOrEqual => actual >= limit,
+        }
+    }
+
+    fn is_upper_bound(&self) -> bool {
+        matches!(self, Comparison::Less | Comparison::LessOrEqual)
+    }
+}
+
+// Parses a latency such as 300ms, 1.5s or 250us into microseconds (milliseconds if there's no unit)
+fn parse_latency(latency: &str) -> Option<f64> {
+    let (value, scale) = if let Some(value) = latency.strip_suffix("ms") {
+        (value, 1_000.0)
+    } else if let Some(value) = latency
+        .strip_suffix("us")
+        .or_else(|| latency.strip_suffix("µs"))
+    {
+        (value, 1.0)
+    } else if let Some(value) = latency.strip_suffix('s') {
+        (value, 1_000_000.0)
+    } else {
+        (latency, 1_000.0)
+    };
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|v| *v >= 0.0)
+        .map(|v| v * scale)
+}
+
+/// Watches the run, stopping it as soon as any of the thresholds is breached for good
+pub(crate) struct ThresholdMonitor {
+    done: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl ThresholdMonitor {
+    pub(crate) fn start(
+        thresholds: Vec<Threshold>,
+        max_requests: u64,
+        state: Arc<RunState>,
+        request_generator: Arc<RequestGenerator>,
+    ) -> ThresholdMonitor {
+        let done = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    thread::sleep(CHECK_INTERVAL);
+
+                    let Some(responses) = &state.responses else {
+                        return;
+                    };
+                    let responses = responses.lock().unwrap();
+                    let progress = Progress {
+                        elapsed: state.load_started.get().map(|started| started.elapsed()),
+                        max_requests,
+                        tally: &responses.tally,
+                    };
+                    if let Some(threshold) = thresholds.iter().find(|t| t.breached(&progress)) {
+                        warn!(
+                            "Threshold {} can no longer be met, stopping the test",
+                            threshold
+                        );
+                        request_generator.stop();
+                        return;
+                    }
+                }
+            })
+        };
+        ThresholdMonitor { done, thread }
+    }
+
+    /// Stops watching the run
+    pub(crate) fn finish(self) {
+        self.done.store(true, Ordering::Relaxed);
+        self.thread.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workers::OutcomeClass;
+
+    // Verify thresholds are parsed into microseconds and fractions, and bad ones rejected
+    #[test]
+    fn thresholds_parsed() {
+        let threshold = Threshold::parse("p99<300ms").unwrap();
+        assert_eq!(Metric::Percentile(99.0), threshold.metric);
+        assert_eq!(Comparison::Less, threshold.comparison);
+        assert_eq!(300_000.0, threshold.limit);
+        assert_eq!("p99<300.00ms", threshold.to_string());
+
+        assert_eq!(1_500_000.0, Threshold::parse("max<=1.5s").unwrap().limit);
+        assert_eq!(250.0, Threshold::parse("p99.9 < 250us").unwrap().limit);
+        assert_eq!(0.005, Threshold::parse("error_rate<0.5%").unwrap().limit);
+        assert_eq!(
+            "error_rate<0.5%",
+            Threshold::parse("error_rate<0.5%").unwrap().to_string()
+        );
+        let rps = Threshold::parse("rps>=1000").unwrap();
+        assert_eq!(Metric::RequestsPerSec, rps.metric);
+        assert_eq!(Comparison::GreaterOrEqual, rps.comparison);
+
+        assert!(Threshold::parse("p99").is_err());
+        assert!(Threshold::parse("p101<1s").is_err());
+        assert!(Threshold::parse("latency<1s").is_err());
+        assert!(Threshold::parse("p99<fast").is_err());
+    }
+
+    // 20 requests taking the given time, the given number of which failed
+    fn tally(latency: u64, errors: u64) -> Tally {
+        let mut tally = Tally::new();
+        for i in 0..20 {
+            let class = match i < errors {
+                true => OutcomeClass::ServerError,
+                false => OutcomeClass::Success,
+            };
+            tally.record(class, latency);
+        }
+        tally
+    }
+
+    // Verify a threshold is only treated as breached once the rest of the run can't save it
+    #[test]
+    fn breached_for_good() {
+        fn progress(tally: &Tally) -> Progress<'_> {
+            Progress {
+                elapsed: Some(Duration::from_secs(10)),
+                max_requests: 1000,
+                tally,
+            }
+        }
+        let slow = tally(500_000, 0);
+
+        // 20 slow requests out of at most 1000 sinks the p99 and max, but not the p50
+        assert!(
+            Threshold::parse("p99<300ms")
+                .unwrap()
+                .breached(&progress(&slow))
+        );
+        assert!(
+            !Threshold::parse("p50<300ms")
+                .unwrap()
+                .breached(&progress(&slow))
+        );
+        assert!(
+            Threshold::parse("max<300ms")
+                .unwrap()
+                .breached(&progress(&slow))
+        );
+
+        // Requests in the same bucket as the limit might be under it, so don't count against it
+        assert!(
+            !Threshold::parse("p99<300ms")
+                .unwrap()
+                .breached(&progress(&tally(300_000, 0)))
+        );
+
+        // 5 errors is 0.5% of the most requests we could make
+        let error_rate = Threshold::parse("error_rate<1%").unwrap();
+        assert!(!error_rate.breached(&progress(&tally(500_000, 5))));
+        assert!(error_rate.breached(&progress(&tally(500_000, 11))));
+
+        // 1000 requests can't be made at 200/s once 10s have gone
+        assert!(
+            Threshold::parse("rps>200")
+                .unwrap()
+                .breached(&progress(&slow))
+        );
+        assert!(
+            !Threshold::parse("rps>50")
+                .unwrap()
+                .breached(&progress(&slow))
+        );
+    }
+
+    // Verify timed out requests count against the latency thresholds, but other failures don't
+    #[test]
+    fn timeouts_breach_latency() {
+        let mut summary = BenchResult::new();
+        summary.tally = tally(100_000, 0);
+        summary.tally.record(OutcomeClass::Timeout, 10_000_000);
+        summary.tally.record(OutcomeClass::Transport, 1_000);
+
+        assert!(
+            !Threshold::parse("p99<300ms")
+                .unwrap()
+                .evaluate(&summary)
+                .passed
+        );
+        assert!(
+            !Threshold::parse("max<5s")
+                .unwrap()
+                .evaluate(&summary)
+                .passed
+        );
+        assert!(
+            Threshold::parse("p50>50ms")
+                .unwrap()
+                .evaluate(&summary)
+                .passed
+        );
+        assert_eq!(
+            "9.09%",
+            Threshold::parse("error_rate<1%")
+                .unwrap()
+                .evaluate(&summary)
+                .actual
+        );
+    }
+
+    // Verify the final verdict is reached from the same counts as the early one
+    #[test]
+    fn evaluated_like_progress() {
+        let mut summary = BenchResult::new();
+        summary.tally = tally(500_000, 11);
+        let progress = Progress {
+            elapsed: None,
+            max_requests: 20,
+            tally: &summary.tally,
+        };
+
+        for threshold in [
+            "max<5s",
+            "max<300ms",
+            "p50<1s",
+            "errors<10",
+            "error_rate<60%",
+        ] {
+            let threshold = Threshold::parse(threshold).unwrap();
+            assert_eq!(
+                threshold.breached(&progress),
+                !threshold.evaluate(&summary).passed,
+                "{}",
+                threshold
+            );
+        }
+    }
+}
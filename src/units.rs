@@ -0,0 +1,41 @@
+/// Formats a latency in microseconds in the most readable unit e.g. 850µs, 12.34ms or 1.50s
+pub(crate) fn format_micros(micros: u64) -> String {
+    match micros {
+        0..1_000 => format!("{}µs", micros),
+        1_000..1_000_000 => format!("{:.2}ms", micros as f64 / 1_000.0),
+        _ => format!("{:.2}s", micros as f64 / 1_000_000.0),
+    }
+}
+
+/// Formats a number of bytes in binary units e.g. 1.5 MiB
+pub(crate) fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{:.0} {}", value, UNITS[unit]),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify values are shown in a sensible unit
+    #[test]
+    fn units_chosen() {
+        assert_eq!("0µs", format_micros(0));
+        assert_eq!("999µs", format_micros(999));
+        assert_eq!("1.00ms", format_micros(1_000));
+        assert_eq!("12.35ms", format_micros(12_345));
+        assert_eq!("1.50s", format_micros(1_500_000));
+        assert_eq!("512 B", format_bytes(512.0));
+        assert_eq!("1.5 KiB", format_bytes(1536.0));
+        assert_eq!("2.0 GiB", format_bytes(2.0 * 1024.0 * 1024.0 * 1024.0));
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+
+use regex::Regex;
+use url::Url;
+
+use crate::urlsource;
+
+// Number of invalid URLs we quote in the load summary
+const INVALID_EXAMPLES: usize = 5;
+
+/// Controls which of the loaded URLs are used in the test (e.g. to strip health checks and bots from access logs)
+#[derive(Default)]
+pub(crate) struct UrlFilter {
+    pub skip_comments: bool,
+    pub skip_blank: bool,
+    pub include: Vec<Regex>,
+    pub exclude: Vec<Regex>,
+    pub dedup: bool,
+    pub sample: Option<f64>,
+    pub first: Option<usize>,
+}
+
+/// Counts of the URLs loaded, and why any were dropped
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct FilterSummary {
+    pub loaded: usize,
+    pub skipped: usize,
+    pub filtered: usize,
+    pub duplicates: usize,
+    pub unsampled: usize,
+    pub truncated: usize,
+    pub invalid: usize,
+    pub invalid_examples: Vec<String>,
+}
+
+impl UrlFilter {
+    /// Filters the raw lines, applies the prefix and drops any URLs that still aren't valid
+    pub(crate) fn apply(
+        &self,
+        lines: Vec<String>,
+        url_prefix: Option<&Url>,
+    ) -> (Vec<String>, FilterSummary) {
+        let mut summary = FilterSummary {
+            loaded: lines.len(),
+            ..FilterSummary::default()
+        };
+        let mut seen = HashSet::new();
+        let mut urls = Vec::new();
+
+        for mut line in lines {
+            if self.first.is_some_and(|first| urls.len() >= first) {
+                summary.truncated += 1;
+                continue;
+            }
+
+            // Comments and blank lines
+            let trimmed = line.trim();
+            if (self.skip_comments && trimmed.starts_with('#'))
+                || (self.skip_blank && trimmed.is_empty())
+            {
+                summary.skipped += 1;
+                continue;
+            }
+
+            // Patterns match the line as it appears in the file (i.e. before any prefix is applied)
+            if (!self.include.is_empty() && !self.include.iter().any(|re| re.is_match(&line)))
+                || self.exclude.iter().any(|re| re.is_match(&line))
+            {
+                summary.filtered += 1;
+                continue;
+            }
+
+            if self.dedup && !seen.insert(line.clone()) {
+                summary.duplicates += 1;
+                continue;
+            }
+
+            if let Some(sample) = self.sample
+                && !rand::random_bool(sample)
+            {
+                summary.unsampled += 1;
+                continue;
+            }
+
+            // Invalid URLs are caught below, so we can ignore any prefix errors
+            if let Some(base) = url_prefix {
+                let _ = urlsource::apply_prefix(base, &mut line);
+            }
+            if !is_valid(urlsource::split_line(&line).0) {
+                summary.invalid += 1;
+                if summary.invalid_examples.len() < INVALID_EXAMPLES {
+                    summary.invalid_examples.push(line);
+                }
+                continue;
+            }
+
+            urls.push(line);
+        }
+
+        (urls, summary)
+    }
+}
+
+impl FilterSummary {
+    /// Logs how many URLs were loaded and dropped
+    pub(crate) fn log(&self, kept: usize) {
+        info!(
+            "Loaded {} URLs, using {} (skipped {}, filtered {}, duplicates {}, not sampled {}, over limit {}, invalid {})",
+            self.loaded,
+            kept,
+            self.skipped,
+            self.filtered,
+            self.duplicates,
+            self.unsampled,
+            self.truncated,
+            self.invalid
+        );
+        if self.invalid > 0 {
+            warn!(
+                "*** Dropped {} invalid URLs, e.g. {}",
+                self.invalid,
+                self.invalid_examples.join(", ")
+            );
+        }
+    }
+}
+
+// We can only request absolute HTTP(S) URLs
+fn is_valid(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    // Verify comments, blank lines, patterns and duplicates are removed and counted
+    #[test]
+    fn filter_lines() {
+        let filter = UrlFilter {
+            skip_comments: true,
+            skip_blank: true,
+            include: vec![Regex::new("^/api/").unwrap()],
+            exclude: vec![Regex::new("health").unwrap()],
+            dedup: true,
+            ..UrlFilter::default()
+        };
+        let base = Url::parse("http://localhost/").unwrap();
+        let input = lines(&[
+            "# header",
+            "/api/a",
+            "",
+            "/api/health",
+            "/static/logo.png",
+            "/api/a",
+            "/api/b",
+        ]);
+
+        let (urls, summary) = filter.apply(input, Some(&base));
+        assert_eq!(
+            lines(&["http://localhost/api/a", "http://localhost/api/b"]),
+            urls
+        );
+        assert_eq!(
+            FilterSummary {
+                loaded: 7,
+                skipped: 2,
+                filtered: 2,
+                duplicates: 1,
+                ..FilterSummary::default()
+            },
+            summary
+        );
+    }
+
+    // Verify we can take the first N URLs, and that invalid URLs are dropped rather than passed through
+    #[test]
+    fn first_and_invalid() {
+        let filter = UrlFilter {
+            first: Some(2),
+            ..UrlFilter::default()
+        };
+        let input = lines(&[
+            "http://a/",
+            "/relative",
+            "ftp://b/",
+            "http://c/",
+            "http://d/",
+        ]);
+
+        let (urls, summary) = filter.apply(input, None);
+        assert_eq!(lines(&["http://a/", "http://c/"]), urls);
+        assert_eq!(2, summary.invalid);
+        assert_eq!(1, summary.truncated);
+        assert_eq!(lines(&["/relative", "ftp://b/"]), summary.invalid_examples);
+    }
+
+    // Verify sampling keeps roughly the requested fraction of URLs
+    #[test]
+    fn sample_fraction() {
+        let filter = UrlFilter {
+            sample: Some(0.25),
+            ..UrlFilter::default()
+        };
+        let input = (0..10000).map(|i| format!("http://a/{}", i)).collect();
+
+        let (urls, summary) = filter.apply(input, None);
+        assert!((2000..3000).contains(&urls.len()));
+        assert_eq!(10000, urls.len() + summary.unsampled);
+    }
+}
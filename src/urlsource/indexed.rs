@@ -0,0 +1,128 @@
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Read, Seek, SeekFrom};
+use std::sync::Mutex;
+
+use url::Url;
+
+use crate::urlsource;
+
+/// Reads URLs on demand from a file via an index of line offsets, so random access doesn't need every URL in memory
+pub(crate) struct OffsetIndex {
+    // Workers take turns to seek and read
+    file: Mutex<fs::File>,
+    // Byte offset of the start of each line, plus a trailing entry for the end of the file
+    offsets: Vec<u64>,
+    url_prefix: Option<Url>,
+}
+
+impl OffsetIndex {
+    /// Scans the file to build the index of line offsets
+    pub(crate) fn build(
+        path: &str,
+        url_prefix: Option<Url>,
+    ) -> Result<OffsetIndex, Box<dyn Error>> {
+        // We can't seek within stdin either
+        if path == urlsource::STDIN {
+            return Err(
+                "Streaming in random order requires a file: URLs on stdin can only be streamed in order (use -o s)"
+                    .into(),
+            );
+        }
+        let mut file =
+            fs::File::open(path).map_err(|e| format!("Unable to open {}: {}", path, e))?;
+
+        // We can't seek within a compressed stream
+        let mut header = [0u8; 8];
+        let header_len = file.read(&mut header)?;
+        file.seek(SeekFrom::Start(0))?;
+        if urlsource::is_compressed(&header[..header_len]) {
+            return Err(format!(
+                "{} is compressed: streaming in random order requires an uncompressed file (or use -o s)",
+                path
+            )
+            .into());
+        }
+
+        info!("Indexing URLs in {}", path);
+        let offsets = scan_offsets(io::BufReader::with_capacity(1 << 20, &file))?;
+        if offsets.len() < 2 {
+            return Err(format!("{} contains no URLs", path).into());
+        }
+        info!("Indexed {} URLs", offsets.len() - 1);
+
+        Ok(OffsetIndex {
+            file: Mutex::new(file),
+            offsets,
+            url_prefix,
+        })
+    }
+
+    /// The number of URLs in the file
+    pub(crate) fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Reads the URL on the given line of the file
+    pub(crate) fn get(&self, index: usize) -> io::Result<String> {
+        let (start, end) = (self.offsets[index], self.offsets[index + 1]);
+        let mut line = vec![0u8; (end - start) as usize];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut line)?;
+        drop(file);
+        Ok(urlsource::decode_line(&line, self.url_prefix.as_ref()))
+    }
+}
+
+// Determines the offset of the start of each line, terminated by the total length. Any per-request headers are checked
+// on the way, as the lines are read again for every request
+fn scan_offsets<R: Read>(mut reader: io::BufReader<R>) -> Result<Vec<u64>, Box<dyn Error>> {
+    let mut offsets = Vec::new();
+    let mut position = 0u64;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let len = reader.read_until(b'\n', &mut line)?;
+        if len == 0 {
+            break;
+        }
+        if line.contains(&b'\t') {
+            let line = String::from_utf8_lossy(&line);
+            urlsource::check_headers(line.trim_end_matches(['\r', '\n']))
+                .map_err(|e| format!("Line {}: {}", offsets.len() + 1, e))?;
+        }
+        offsets.push(position);
+        position += len as u64;
+    }
+    offsets.push(position);
+
+    Ok(offsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify stdin is rejected, as it can't be read in random order
+    #[test]
+    fn stdin_rejected() {
+        let error = OffsetIndex::build(urlsource::STDIN, None).err().unwrap();
+        assert!(error.to_string().contains("-o s"));
+    }
+
+    // Verify we find the start of each line, with or without a trailing newline
+    #[test]
+    fn line_offsets() {
+        let offsets = scan_offsets(io::BufReader::new("/a\n/bc\r\n/d".as_bytes())).unwrap();
+        assert_eq!(vec![0, 3, 8, 10], offsets);
+
+        let offsets = scan_offsets(io::BufReader::new("/a\n".as_bytes())).unwrap();
+        assert_eq!(vec![0, 3], offsets);
+
+        let offsets = scan_offsets(io::BufReader::new("/a\tAccept: */*\r\n".as_bytes())).unwrap();
+        assert_eq!(vec![0, 16], offsets);
+        assert!(scan_offsets(io::BufReader::new("/a\n/b\tAccept\n".as_bytes())).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+
+use flate2::read::MultiGzDecoder;
+use liblzma::read::XzDecoder;
+use url::Url;
+
+use crate::headers;
+
+pub(crate) mod filter;
+pub(crate) mod indexed;
+pub(crate) mod sitemap;
+pub(crate) mod streaming;
+
+/// The path used to read from stdin rather than a file
+pub(crate) const STDIN: &str = "-";
+
+// Magic bytes at the start of each supported compressed stream
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+// Compression formats we can transparently decode
+#[derive(Debug, PartialEq)]
+enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+/// The set of URLs requested during the test
+pub(crate) enum UrlSource {
+    /// Every URL loaded into memory up front
+    Memory(Vec<String>),
+    /// URLs read on demand from a file using an index of line offsets (random order)
+    Indexed(indexed::OffsetIndex),
+    /// URLs read lazily from a file through a bounded prefetch queue (sequential order)
+    Streaming(streaming::StreamingReader),
+}
+
+impl UrlSource {
+    /// The number of distinct URLs. Streaming sources don't know this until the whole file has been read (they wrap
+    /// back to the start of the file themselves), so have no count
+    pub(crate) fn len(&self) -> Option<usize> {
+        match self {
+            UrlSource::Memory(urls) => Some(urls.len()),
+            UrlSource::Indexed(index) => Some(index.len()),
+            UrlSource::Streaming(_) => None,
+        }
+    }
+
+    /// Returns the URL for the given index, along with the index of the URL actually returned (streaming sources ignore
+    /// the requested index and hand out the next line in the file). Returns None if no URL could be read
+    pub(crate) fn fetch(&self, url_index: usize) -> Option<(usize, Cow<'_, str>)> {
+        match self {
+            UrlSource::Memory(urls) => urls
+                .get(url_index)
+                .map(|url| (url_index, Cow::Borrowed(url.as_str()))),
+            UrlSource::Indexed(index) => match index.get(url_index) {
+                Ok(url) => Some((url_index, Cow::Owned(url))),
+                Err(e) => {
+                    error!("Unable to read URL {}: {}", url_index, e);
+                    None
+                }
+            },
+            UrlSource::Streaming(reader) => {
+                reader.next().map(|(line, url)| (line, Cow::Owned(url)))
+            }
+        }
+    }
+
+    /// Looks up the URLs for a set of indexes (e.g. to produce a report after the test)
+    pub(crate) fn lookup(
+        &self,
+        url_indexes: &HashSet<usize>,
+    ) -> Result<HashMap<usize, String>, Box<dyn Error>> {
+        match self {
+            UrlSource::Memory(urls) => Ok(url_indexes
+                .iter()
+                .filter_map(|&i| urls.get(i).map(|url| (i, url.clone())))
+                .collect()),
+            UrlSource::Indexed(index) => url_indexes
+                .iter()
+                .map(|&i| Ok((i, index.get(i)?)))
+                .collect(),
+            UrlSource::Streaming(reader) => reader.lookup(url_indexes),
+        }
+    }
+}
+
+/// Prepends the prefix to the URL if it is relative (e.g. just a path+query string from a load-balancer log)
+pub(crate) fn apply_prefix(base: &Url, line: &mut String) -> Result<(), url::ParseError> {
+    let (url, headers) = split_line(line);
+    match Url::parse(url) {
+        // Nothing required in the OK case
+        Ok(_) => Ok(()),
+        // If no base, then fix
+        Err(url::ParseError::RelativeUrlWithoutBase) => {
+            let mut prefixed: String = base.join(url)?.into();
+            if !headers.is_empty() {
+                prefixed.push('\t');
+                prefixed.push_str(headers);
+            }
+            *line = prefixed;
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Splits a line from the URL file into the URL and any per-request headers that follow it (separated by tabs, which
+/// can't appear in a URL unencoded)
+pub(crate) fn split_line(line: &str) -> (&str, &str) {
+    line.split_once('\t').unwrap_or((line, ""))
+}
+
+/// Checks the per-request headers on a line of the URL file, so they needn't be checked as each request is sent
+pub(crate) fn check_headers(line: &str) -> Result<(), Box<dyn Error>> {
+    let (url, inline_headers) = split_line(line);
+    match headers::parse_inline(inline_headers) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Invalid headers for {}: {}", url, e).into()),
+    }
+}
+
+// Converts a raw line read from a file into a URL, stripping the line ending and applying the prefix
+fn decode_line(line: &[u8], url_prefix: Option<&Url>) -> String {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let mut url = String::from_utf8_lossy(line).into_owned();
+    if let Some(base) = url_prefix
+        && let Err(e) = apply_prefix(base, &mut url)
+    {
+        warn!("URL {} is invalid: {}", url, e);
+    }
+    url
+}
+
+/// Opens a local file (or stdin, given "-") for reading, transparently decompressing it if required
+pub(crate) fn open_file(path: &str) -> Result<Box<dyn BufRead + Send>, Box<dyn Error>> {
+    if path == STDIN {
+        return decode(Box::new(io::BufReader::new(io::stdin())));
+    }
+
+    let file = fs::File::open(path).map_err(|e| format!("Unable to open {}: {}", path, e))?;
+    decode(Box::new(io::BufReader::new(file)))
+}
+
+/// Wraps the reader in a decompressor if the stream starts with a known compression signature
+fn decode(mut reader: Box<dyn BufRead + Send>) -> Result<Box<dyn BufRead + Send>, Box<dyn Error>> {
+    let compression = detect_compression(reader.fill_buf()?);
+    if let Some(compression) = &compression {
+        debug!("Decompressing {:?} input", compression);
+    }
+
+    Ok(match compression {
+        Some(Compression::Gzip) => Box::new(io::BufReader::new(MultiGzDecoder::new(reader))),
+        Some(Compression::Zstd) => Box::new(io::BufReader::new(
+            zstd::stream::read::Decoder::with_buffer(reader)?,
+        )),
+        Some(Compression::Xz) => Box::new(io::BufReader::new(XzDecoder::new_multi_decoder(reader))),
+        None => reader,
+    })
+}
+
+// Checks the leading bytes of a stream for a known compression signature
+fn detect_compression(header: &[u8]) -> Option<Compression> {
+    if header.starts_with(GZIP_MAGIC) {
+        Some(Compression::Gzip)
+    } else if header.starts_with(ZSTD_MAGIC) {
+        Some(Compression::Zstd)
+    } else if header.starts_with(XZ_MAGIC) {
+        Some(Compression::Xz)
+    } else {
+        None
+    }
+}
+
+// Checks whether the stream is compressed (and so can't be read at arbitrary offsets)
+fn is_compressed(header: &[u8]) -> bool {
+    detect_compression(header).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify line endings are stripped and relative URLs are prefixed
+    #[test]
+    fn decode_lines() {
+        let base = Url::parse("http://localhost:8070/").unwrap();
+        assert_eq!(
+            "http://localhost:8070/abc?d=1",
+            decode_line(b"/abc?d=1\r\n", Some(&base))
+        );
+        assert_eq!(
+            "http://other/abc",
+            decode_line(b"http://other/abc\n", Some(&base))
+        );
+        assert_eq!("/abc", decode_line(b"/abc", None));
+        assert_eq!(
+            "http://localhost:8070/abc\tAccept: text/html",
+            decode_line(b"/abc\tAccept: text/html\n", Some(&base))
+        );
+    }
+
+    // Verify each compression format is detected and decoded transparently
+    #[test]
+    fn decode_compressed() {
+        use std::io::{Read, Write};
+
+        let plain = b"/a\n/b\n".to_vec();
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(&plain).unwrap();
+        let zstd = zstd::stream::encode_all(plain.as_slice(), 0).unwrap();
+        let mut xz = liblzma::write::XzEncoder::new(Vec::new(), 1);
+        xz.write_all(&plain).unwrap();
+
+        for (expected, encoded) in [
+            (None, plain.clone()),
+            (Some(Compression::Gzip), gzip.finish().unwrap()),
+            (Some(Compression::Zstd), zstd),
+            (Some(Compression::Xz), xz.finish().unwrap()),
+        ] {
+            assert_eq!(expected, detect_compression(&encoded));
+
+            let mut decoded = Vec::new();
+            decode(Box::new(io::Cursor::new(encoded)))
+                .unwrap()
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert_eq!(plain, decoded);
+        }
+    }
+}
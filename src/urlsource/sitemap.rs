@@ -0,0 +1,249 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+use quick_xml::Reader;
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::Event;
+use url::Url;
+
+use crate::urlsource;
+
+// Guard against sitemap indexes that (directly or indirectly) refer to each other
+const MAX_DEPTH: usize = 8;
+
+/// The contents of a single sitemap document
+#[derive(Debug, PartialEq)]
+enum Sitemap {
+    // A <urlset> listing the pages of the site
+    UrlSet(Vec<String>),
+    // A <sitemapindex> listing further sitemaps
+    Index(Vec<String>),
+}
+
+/// Loads the page URLs from a local sitemap file, following any sitemap index entries (from local files, including
+/// downloaded copies of the sitemaps an index lists by URL)
+pub(crate) fn load(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let path = Path::new(path);
+    let base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+
+    let mut urls = Vec::new();
+    let mut visited = HashSet::new();
+    collect(path, &base_dir, &mut visited, &mut urls, 0)?;
+
+    Ok(urls)
+}
+
+// Finds the file for a sitemap index entry: a file:// URL, or a path relative to the index. Sitemaps on the web
+// aren't fetched, so an http(s) entry is read from the file of the same name next to the index - i.e. the sitemaps
+// must be downloaded alongside it first
+fn resolve(loc: &str, base_dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    match Url::parse(loc) {
+        Ok(url) if url.scheme() == "file" => url
+            .to_file_path()
+            .map_err(|_| format!("Invalid file URL {} in sitemap index", loc).into()),
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+            let name = url
+                .path_segments()
+                .and_then(|mut segments| segments.next_back())
+                .filter(|name| !name.is_empty())
+                .ok_or_else(|| format!("Sitemap index entry {} doesn't name a file", loc))?;
+            let path = base_dir.join(name);
+            if !path.is_file() {
+                return Err(format!(
+                    "Sitemap index entry {} isn't fetched, and {} doesn't exist. Download the sitemap alongside the index",
+                    loc,
+                    path.display()
+                )
+                .into());
+            }
+            Ok(path)
+        }
+        Ok(_) => Err(format!("Unsupported sitemap index entry {}", loc).into()),
+        // Not a URL, so treat it as a path relative to the index
+        Err(_) => Ok(base_dir.join(loc)),
+    }
+}
+
+// Reads the sitemap, appending page URLs and recursing into any sitemap index entries
+fn collect(
+    path: &Path,
+    base_dir: &Path,
+    visited: &mut HashSet<PathBuf>,
+    urls: &mut Vec<String>,
+    depth: usize,
+) -> Result<(), Box<dyn Error>> {
+    if depth > MAX_DEPTH || !visited.insert(path.to_path_buf()) {
+        warn!(
+            "Skipping sitemap {} (already visited or nested too deeply)",
+            path.display()
+        );
+        return Ok(());
+    }
+
+    info!("Loading sitemap {}", path.display());
+    match parse(urlsource::open_file(&path.to_string_lossy())?)? {
+        Sitemap::UrlSet(locs) => urls.extend(locs),
+        Sitemap::Index(locs) => {
+            for loc in locs {
+                let child = resolve(&loc, base_dir)?;
+                collect(&child, base_dir, visited, urls, depth + 1)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Extracts the <loc> values from a sitemap or sitemap index document
+fn parse<R: BufRead>(reader: R) -> Result<Sitemap, Box<dyn Error>> {
+    let mut reader = Reader::from_reader(reader);
+    let mut buf = Vec::new();
+
+    let mut is_index = None;
+    let mut locs = Vec::new();
+    let mut loc: Option<String> = None;
+
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => match e.local_name().as_ref() {
+                "urlset" if is_index.is_none() => is_index = Some(false),
+                "sitemapindex" if is_index.is_none() => is_index = Some(true),
+                "loc" => loc = Some(String::new()),
+                _ => {}
+            },
+            Event::Text(e) => {
+                if let Some(loc) = loc.as_mut() {
+                    loc.push_str(&e.xml10_content());
+                }
+            }
+            Event::CData(e) => {
+                if let Some(loc) = loc.as_mut() {
+                    loc.push_str(&e.xml10_content());
+                }
+            }
+            Event::GeneralRef(e) => {
+                if let Some(loc) = loc.as_mut() {
+                    if let Some(ch) = e.resolve_char_ref()? {
+                        loc.push(ch);
+                    } else if let Some(entity) = resolve_predefined_entity(&e) {
+                        loc.push_str(entity);
+                    } else {
+                        return Err(format!("Unknown entity &{}; in sitemap", &*e).into());
+                    }
+                }
+            }
+            Event::End(e) if e.local_name().as_ref() == "loc" => {
+                if let Some(loc) = loc.take() {
+                    let loc = loc.trim();
+                    if !loc.is_empty() {
+                        locs.push(loc.to_string());
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    match is_index {
+        Some(true) => Ok(Sitemap::Index(locs)),
+        Some(false) => Ok(Sitemap::UrlSet(locs)),
+        None => Err("Document is not a sitemap (expected <urlset> or <sitemapindex>)".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify we extract the page URLs from a sitemap, unescaping entities
+    #[test]
+    fn parse_urlset() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+              <url><loc>http://example.com/</loc><priority>1.0</priority></url>
+              <url><loc>
+                http://example.com/search?q=a&amp;page=2
+              </loc></url>
+            </urlset>"#;
+
+        let expected = Sitemap::UrlSet(vec![
+            "http://example.com/".to_string(),
+            "http://example.com/search?q=a&page=2".to_string(),
+        ]);
+        assert_eq!(expected, parse(xml.as_bytes()).unwrap());
+    }
+
+    // Verify we recognise a sitemap index
+    #[test]
+    fn parse_index() {
+        let xml = r#"<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+              <sitemap><loc>http://example.com/sitemap-1.xml.gz</loc></sitemap>
+            </sitemapindex>"#;
+
+        let expected = Sitemap::Index(vec!["http://example.com/sitemap-1.xml.gz".to_string()]);
+        assert_eq!(expected, parse(xml.as_bytes()).unwrap());
+    }
+
+    // Verify index entries are read from local files, with http(s) entries found next to the index
+    #[test]
+    fn resolve_location() {
+        let dir = Path::new("/data/sitemaps");
+        assert_eq!(
+            dir.join("sitemap-2.xml"),
+            resolve("sitemap-2.xml", dir).unwrap()
+        );
+        assert_eq!(
+            PathBuf::from("/tmp/sitemap-3.xml"),
+            resolve("file:///tmp/sitemap-3.xml", dir).unwrap()
+        );
+        assert!(resolve("http://example.com/sitemap-1.xml", dir).is_err());
+        assert!(resolve("http://example.com/", dir).is_err());
+        assert!(resolve("ftp://example.com/sitemap-1.xml", dir).is_err());
+    }
+
+    // Verify a sitemap index listing absolute URLs is followed to the downloaded sitemaps
+    #[test]
+    fn load_index() {
+        let dir = std::env::temp_dir().join(format!("hb-sitemap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("sitemap.xml"),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+              <sitemap><loc>https://example.com/sitemaps/pages.xml</loc><lastmod>2024-01-01</lastmod></sitemap>
+              <sitemap><loc>https://example.com/sitemaps/posts.xml?v=2</loc></sitemap>
+            </sitemapindex>"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("pages.xml"),
+            r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+              <url><loc>https://example.com/</loc></url>
+            </urlset>"#,
+        )
+        .unwrap();
+        let index = dir.join("sitemap.xml");
+
+        // posts.xml hasn't been downloaded
+        assert!(load(&index.to_string_lossy()).is_err());
+
+        std::fs::write(
+            dir.join("posts.xml"),
+            r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+              <url><loc>https://example.com/posts/1</loc></url>
+            </urlset>"#,
+        )
+        .unwrap();
+        let urls = load(&index.to_string_lossy());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            vec!["https://example.com/", "https://example.com/posts/1"],
+            urls.unwrap()
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::BufRead;
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::thread;
+
+use url::Url;
+
+use crate::urlsource;
+
+/// Reads URLs lazily from a file on a background thread, feeding the workers through a bounded prefetch queue. When the
+/// end of the file is reached it starts again from the beginning (except for stdin, which ends the stream)
+pub(crate) struct StreamingReader {
+    path: String,
+    url_prefix: Option<Url>,
+    queue: Mutex<Receiver<(usize, String)>>,
+}
+
+impl StreamingReader {
+    /// Opens the file and starts prefetching up to `prefetch` URLs
+    pub(crate) fn open(
+        path: &str,
+        prefetch: usize,
+        url_prefix: Option<Url>,
+    ) -> Result<StreamingReader, Box<dyn Error>> {
+        // Check the file is readable (and not empty) before the test starts, otherwise we'd spin forever at EOF
+        let mut reader = urlsource::open_file(path)?;
+        if reader.fill_buf()?.is_empty() {
+            return Err(format!("{} contains no URLs", path).into());
+        }
+
+        let (sender, receiver) = sync_channel(prefetch);
+        let thread_path = path.to_string();
+        let thread_prefix = url_prefix.clone();
+        thread::spawn(move || {
+            if let Err(e) = prefetch_urls(reader, &thread_path, thread_prefix.as_ref(), sender) {
+                error!("Error streaming URLs from {}: {}", thread_path, e);
+            }
+        });
+
+        info!("Streaming URLs from {} (prefetching {})", path, prefetch);
+        Ok(StreamingReader {
+            path: path.to_string(),
+            url_prefix,
+            queue: Mutex::new(receiver),
+        })
+    }
+
+    /// Returns the next URL in the file with its line number, or None if the file could not be read
+    pub(crate) fn next(&self) -> Option<(usize, String)> {
+        self.queue.lock().unwrap().recv().ok()
+    }
+
+    /// Re-reads the file to find the URLs on the nominated lines
+    pub(crate) fn lookup(
+        &self,
+        lines: &HashSet<usize>,
+    ) -> Result<HashMap<usize, String>, Box<dyn Error>> {
+        let mut found = HashMap::new();
+        if self.path == urlsource::STDIN {
+            warn!("Unable to re-read URLs from stdin for reporting");
+            return Ok(found);
+        }
+
+        let mut reader = urlsource::open_file(&self.path)?;
+        let mut line = Vec::new();
+        let mut index = 0;
+        while found.len() < lines.len() {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            if lines.contains(&index) {
+                found.insert(
+                    index,
+                    urlsource::decode_line(&line, self.url_prefix.as_ref()),
+                );
+            }
+            index += 1;
+        }
+
+        Ok(found)
+    }
+}
+
+// Pushes URLs onto the queue until the workers go away, wrapping back to the start of the file as required
+fn prefetch_urls(
+    mut reader: Box<dyn BufRead + Send>,
+    path: &str,
+    url_prefix: Option<&Url>,
+    queue: SyncSender<(usize, String)>,
+) -> Result<(), Box<dyn Error>> {
+    let mut line = Vec::new();
+    let mut index = 0;
+    let mut first_pass = true;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            if path == urlsource::STDIN {
+                info!("Reached the end of the URLs on stdin after {} URLs", index);
+                return Ok(());
+            }
+            debug!(
+                "Reached the end of {} after {} URLs, restarting",
+                path, index
+            );
+            reader = urlsource::open_file(path)?;
+            index = 0;
+            first_pass = false;
+            continue;
+        }
+
+        let mut url = urlsource::decode_line(&line, url_prefix);
+        // Bad headers are dropped, but only reported the first time through the file
+        if let Err(e) = urlsource::check_headers(&url) {
+            if first_pass {
+                warn!(
+                    "Ignoring the headers on line {} of {}: {}",
+                    index + 1,
+                    path,
+                    e
+                );
+            }
+            url.truncate(urlsource::split_line(&url).0.len());
+        }
+        if queue.send((index, url)).is_err() {
+            // Nobody left to consume the URLs
+            return Ok(());
+        }
+        index += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    // Writes the URLs to a temporary file, returning its path
+    fn url_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("hb-{}-{}.txt", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    // Hands out one line at a time, counting the lines read so far
+    struct CountingReader {
+        lines: Vec<Vec<u8>>,
+        // The line being read, and how far through it we are
+        line: usize,
+        offset: usize,
+        started: Arc<AtomicUsize>,
+    }
+
+    impl Read for CountingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let available = self.fill_buf()?;
+            let len = available.len().min(buf.len());
+            buf[..len].copy_from_slice(&available[..len]);
+            self.consume(len);
+            Ok(len)
+        }
+    }
+
+    impl BufRead for CountingReader {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            let Some(line) = self.lines.get(self.line) else {
+                return Ok(&[]);
+            };
+            self.started.fetch_max(self.line + 1, Ordering::Relaxed);
+            Ok(&line[self.offset..])
+        }
+
+        fn consume(&mut self, amount: usize) {
+            self.offset += amount;
+            if self.offset == self.lines[self.line].len() {
+                self.line += 1;
+                self.offset = 0;
+            }
+        }
+    }
+
+    // Verify URLs are only read ahead as far as the queue allows
+    #[test]
+    fn prefetch_bounded() {
+        let started = Arc::new(AtomicUsize::new(0));
+        let reader = CountingReader {
+            lines: (0..10).map(|i| format!("/{}\n", i).into_bytes()).collect(),
+            line: 0,
+            offset: 0,
+            started: started.clone(),
+        };
+        let (sender, receiver) = sync_channel(2);
+        thread::spawn(move || {
+            prefetch_urls(Box::new(reader), "urls.txt", None, sender).unwrap();
+        });
+
+        // Two URLs wait in the queue, and a third is blocked waiting to join them
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(3, started.load(Ordering::Relaxed));
+        assert_eq!((0, "/0".to_string()), receiver.recv().unwrap());
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(4, started.load(Ordering::Relaxed));
+    }
+
+    // Verify the file is read again from the top once the end is reached, with bad headers dropped
+    #[test]
+    fn restarts_at_end() {
+        let path = url_file("stream", "/a\n/b\tAccept\r\n/c\tAccept: */*\n");
+        let reader =
+            StreamingReader::open(&path, 1, Url::parse("http://example.com").ok()).unwrap();
+        let urls = (0..4).map(|_| reader.next()).collect::<Option<Vec<_>>>();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(
+            vec![
+                (0, "http://example.com/a".to_string()),
+                (1, "http://example.com/b".to_string()),
+                (2, "http://example.com/c\tAccept: */*".to_string()),
+                (0, "http://example.com/a".to_string()),
+            ],
+            urls.unwrap()
+        );
+    }
+
+    // Verify the stream ends with stdin rather than starting again
+    #[test]
+    fn stdin_ends() {
+        let (sender, receiver) = sync_channel(4);
+        prefetch_urls(
+            Box::new("/a\n/b".as_bytes()),
+            urlsource::STDIN,
+            None,
+            sender,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![(0, "/a".to_string()), (1, "/b".to_string())],
+            receiver.iter().collect::<Vec<_>>()
+        );
+    }
+
+    // Verify the URLs on given lines are found by reading the file again
+    #[test]
+    fn lookup_lines() {
+        let path = url_file("lookup", "/a\n/b\n/c\n/d\n");
+        let reader =
+            StreamingReader::open(&path, 1, Url::parse("http://example.com").ok()).unwrap();
+        let found = reader.lookup(&HashSet::from([1, 3, 7]));
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(
+            HashMap::from([
+                (1, "http://example.com/b".to_string()),
+                (3, "http://example.com/d".to_string()),
+            ]),
+            found.unwrap()
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use std::cell::OnceCell;
+use std::error::Error;
+use std::fmt;
+
+use regex::bytes::Regex;
+use serde_json::Value;
+use ureq::http::HeaderMap;
+
+/// A check applied to every response, so that (for example) a 200 containing an error page isn't counted as a success
+#[derive(Clone, Debug)]
+pub(crate) enum Assertion {
+    // The status is one of these codes or classes (e.g. 200 or 2xx), kept alongside the original text for reporting
+    Status(Vec<(u16, u16)>, String),
+    BodyContains(String),
+    BodyMatches(Regex),
+    // The value at a JSON pointer equals the expected value (compared as JSON, or as a plain string)
+    JsonEquals { pointer: String, expected: String },
+    HeaderPresent(String),
+    // The body size in bytes is within these (inclusive) bounds
+    BodySize { min: usize, max: usize },
+}
+
+impl Assertion {
+    /// Parses a comma separated list of status codes and classes e.g. 200,201,3xx
+    pub(crate) fn status(codes: &str) -> Result<Assertion, Box<dyn Error>> {
+        let ranges = codes
+            .split(',')
+            .map(|code| parse_status(code.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Assertion::Status(ranges, codes.to_string()))
+    }
+
+    /// Parses a JSON pointer and expected value e.g. /status=ok
+    pub(crate) fn json(expression: &str) -> Result<Assertion, Box<dyn Error>> {
+        match expression.split_once('=') {
+            Some((pointer, expected)) if pointer.is_empty() || pointer.starts_with('/') => {
+                Ok(Assertion::JsonEquals {
+                    pointer: pointer.to_string(),
+                    expected: expected.to_string(),
+                })
+            }
+            _ => Err(format!(
+                "Invalid JSON assertion '{}': expected /pointer=value",
+                expression
+            )
+            .into()),
+        }
+    }
+
+    /// Parses body size bounds e.g. 100..5000, 100.. or ..5000
+    pub(crate) fn body_size(bounds: &str) -> Result<Assertion, Box<dyn Error>> {
+        let invalid = || format!("Invalid body size '{}': expected min..max", bounds);
+        let (min, max) = bounds.split_once("..").ok_or_else(invalid)?;
+        let min = match min.trim() {
+            "" => 0,
+            min => min.parse().map_err(|_| invalid())?,
+        };
+        let max = match max.trim() {
+            "" => usize::MAX,
+            max => max.parse().map_err(|_| invalid())?,
+        };
+        if min > max {
+            return Err(invalid().into());
+        }
+        Ok(Assertion::BodySize { min, max })
+    }
+
+    // Whether checking the assertion needs the response body
+    pub(crate) fn needs_body(&self) -> bool {
+        !matches!(self, Assertion::Status(..) | Assertion::HeaderPresent(_))
+    }
+
+    fn check(&self, response: &CheckedResponse) -> bool {
+        match self {
+            Assertion::Status(ranges, _) => ranges
+                .iter()
+                .any(|(from, to)| (*from..=*to).contains(&response.status)),
+            Assertion::BodyContains(text) => {
+                text.is_empty()
+                    || response
+                        .body
+                        .windows(text.len())
+                        .any(|window| window == text.as_bytes())
+            }
+            Assertion::BodyMatches(regex) => regex.is_match(response.body),
+            Assertion::JsonEquals { pointer, expected } => response
+                .json()
+                .and_then(|json| json.pointer(pointer))
+                .is_some_and(|actual| {
+                    actual.as_str() == Some(expected.as_str())
+                        || serde_json::from_str::<Value>(expected).is_ok_and(|e| &e == actual)
+                }),
+            Assertion::HeaderPresent(name) => response.headers.contains_key(name.as_str()),
+            Assertion::BodySize { min, max } => (*min..=*max).contains(&response.body.len()),
+        }
+    }
+}
+
+impl fmt::Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Assertion::Status(_, codes) => write!(f, "status in {}", codes),
+            Assertion::BodyContains(text) => write!(f, "body contains {:?}", text),
+            Assertion::BodyMatches(regex) => write!(f, "body matches /{}/", regex),
+            Assertion::JsonEquals { pointer, expected } => write!(f, "{} == {}", pointer, expected),
+            Assertion::HeaderPresent(name) => write!(f, "header {} present", name),
+            Assertion::BodySize { min, max } if *max == usize::MAX => {
+                write!(f, "body size >= {}", min)
+            }
+            Assertion::BodySize { min, max } => write!(f, "body size in {}..{}", min, max),
+        }
+    }
+}
+
+/// Parses a status code or class (e.g. 404 or 5xx) into the range of codes it covers
+pub(crate) fn parse_status(code: &str) -> Result<(u16, u16), String> {
+    match code.strip_suffix("xx") {
+        Some(class) => match class.parse::<u16>() {
+            Ok(class @ 1..=5) => Ok((class * 100, class * 100 + 99)),
+            _ => Err(format!("Invalid status class '{}'", code)),
+        },
+        None => match code.parse::<u16>() {
+            Ok(code @ 100..=599) => Ok((code, code)),
+            _ => Err(format!("Invalid status code '{}'", code)),
+        },
+    }
+}
+
+/// The parts of a response the assertions look at. The body is only parsed as JSON if an assertion needs it
+pub(crate) struct CheckedResponse<'a> {
+    pub status: u16,
+    pub headers: &'a HeaderMap,
+    pub body: &'a [u8],
+    json: OnceCell<Option<Value>>,
+}
+
+impl<'a> CheckedResponse<'a> {
+    pub(crate) fn new(status: u16, headers: &'a HeaderMap, body: &'a [u8]) -> CheckedResponse<'a> {
+        CheckedResponse {
+            status,
+            headers,
+            body,
+            json: OnceCell::new(),
+        }
+    }
+
+    fn json(&self) -> Option<&Value> {
+        self.json
+            .get_or_init(|| serde_json::from_slice(self.body).ok())
+            .as_ref()
+    }
+}
+
+/// Returns the assertions the response fails
+pub(crate) fn check<'a>(
+    assertions: &'a [Assertion],
+    response: &CheckedResponse,
+) -> Vec<&'a Assertion> {
+    assertions
+        .iter()
+        .filter(|assertion| !assertion.check(response))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify each kind of assertion passes and fails as expected
+    #[test]
+    fn assertions_checked() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", "abc".parse().unwrap());
+        let body = br#"{"status": "ok", "count": 3}"#;
+        let response = CheckedResponse::new(200, &headers, body);
+
+        let passing = [
+            Assertion::status("201,2xx").unwrap(),
+            Assertion::BodyContains("ok".to_string()),
+            Assertion::BodyMatches(Regex::new(r#""count": \d"#).unwrap()),
+            Assertion::json("/status=ok").unwrap(),
+            Assertion::json("/count=3").unwrap(),
+            Assertion::HeaderPresent("X-Request-Id".to_string()),
+            Assertion::body_size("10..").unwrap(),
+        ];
+        assert!(check(&passing, &response).is_empty());
+
+        let failing = [
+            Assertion::status("3xx,404").unwrap(),
+            Assertion::BodyContains("error".to_string()),
+            Assertion::json("/status=error").unwrap(),
+            Assertion::json("/missing=1").unwrap(),
+            Assertion::HeaderPresent("x-missing".to_string()),
+            Assertion::body_size("..10").unwrap(),
+        ];
+        assert_eq!(failing.len(), check(&failing, &response).len());
+    }
+
+    // Verify invalid assertions are rejected
+    #[test]
+    fn invalid_assertions() {
+        assert!(Assertion::status("6xx").is_err());
+        assert!(Assertion::status("20").is_err());
+        assert!(Assertion::json("status=ok").is_err());
+        assert!(Assertion::body_size("10").is_err());
+        assert!(Assertion::body_size("10..5").is_err());
+    }
+}
@@ -1,20 +1,79 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Barrier, Mutex, OnceLock};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::auth::Auth;
 use crate::config::HttpMethod;
+use crate::dashboard::Dashboard;
+use crate::headers;
+use crate::headers::{Header, RequestHeader};
 use crate::requestgen::RequestGenerator;
+use crate::signing::{Signer, SigningRequest};
+use crate::template::{RequestContext, Template};
+use crate::urlsource;
+use crate::urlsource::UrlSource;
+use crate::workers::assertions::{Assertion, CheckedResponse};
+use crate::workers::phases::{Connection, Phase};
+use crate::workers::retry::{Outcome, RetryPolicy};
+use crate::workers::session::{Session, SessionPolicy};
+use crate::workers::setup::SetupStep;
+use crate::workers::timeline::{IntervalRecorder, Timeline};
+use crate::workers::transport::TransportError;
 use hdrhistogram::Histogram;
-use ureq::{Agent, Error};
+use ureq::http::Response;
+use ureq::http::header::SET_COOKIE;
+use ureq::{Agent, Body, RequestBuilder};
+use url::Url;
+
+pub(crate) mod assertions;
+pub(crate) mod phases;
+pub(crate) mod retry;
+pub(crate) mod session;
+pub(crate) mod setup;
+pub(crate) mod timeline;
+pub(crate) mod transport;
 
 /// Statistics we generate during the benchmark process
 pub(crate) struct BenchResult {
     pub status: HashMap<u16, u32>,
     pub request_errors: u32,
     pub response_errors: u32,
+    // Bytes of response body received (after any decompression) and of request body sent
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    // Requests completed and bytes transferred in each second of the test
+    pub per_second: Vec<Transfer>,
+    // Failures to get a (complete) response, by cause
+    pub transport_errors: HashMap<TransportError, u32>,
     pub latency: Histogram<u64>,
+    // What the thresholds are checked against
+    pub tally: Tally,
+    // With retries, the latency of each request's first attempt, and of all its attempts (including backoff)
+    pub first_attempt_latency: Histogram<u64>,
+    pub end_to_end_latency: Histogram<u64>,
+    pub retries: u64,
+    pub retried_requests: u32,
+    pub retries_exhausted: u32,
+    pub class_latency: BTreeMap<OutcomeClass, Histogram<u64>>,
+    // Time spent in each phase of the requests (as far as failed requests got), split by new and reused connections
+    pub phase_latency: BTreeMap<(Connection, Phase), Histogram<u64>>,
     pub request_times: Vec<(usize, u64)>,
+    pub sessions: u64,
+    pub setup_latency: Histogram<u64>,
+    pub setup_errors: u32,
+    // Responses failing at least one assertion, and the number of failures of each assertion
+    pub assertion_failures: u32,
+    pub failed_assertions: HashMap<String, u32>,
+    // Requests and latency over the course of the run (filled in once the run completes)
+    pub timeline: Timeline,
+    // Time spent generating load (i.e. excluding any setup phase)
+    pub run_time: Duration,
 }
 
 impl BenchResult {
@@ -24,9 +83,27 @@ impl BenchResult {
             status: HashMap::new(),
             request_errors: 0,
             response_errors: 0,
-            // We measure latency in milliseconds, so configure the histogram to track 1 millisecond to 100 seconds
-            latency: Histogram::<u64>::new_with_bounds(1, 1000 * 100, 2).unwrap(),
+            bytes_received: 0,
+            bytes_sent: 0,
+            per_second: Vec::new(),
+            transport_errors: HashMap::new(),
+            latency: latency_histogram(),
+            tally: Tally::new(),
+            class_latency: BTreeMap::new(),
+            phase_latency: BTreeMap::new(),
+            first_attempt_latency: latency_histogram(),
+            end_to_end_latency: latency_histogram(),
+            retries: 0,
+            retried_requests: 0,
+            retries_exhausted: 0,
             request_times: Vec::new(),
+            sessions: 0,
+            setup_latency: latency_histogram(),
+            setup_errors: 0,
+            assertion_failures: 0,
+            failed_assertions: HashMap::new(),
+            timeline: Timeline::new(Duration::from_secs(1)),
+            run_time: Duration::ZERO,
         }
     }
 
@@ -39,25 +116,240 @@ impl BenchResult {
 
         summary.request_errors += self.request_errors;
         summary.response_errors += self.response_errors;
+        summary.bytes_received += self.bytes_received;
+        summary.bytes_sent += self.bytes_sent;
+        if summary.per_second.len() < self.per_second.len() {
+            summary
+                .per_second
+                .resize(self.per_second.len(), Transfer::default());
+        }
+        for (total, second) in summary.per_second.iter_mut().zip(&self.per_second) {
+            total.requests += second.requests;
+            total.bytes_received += second.bytes_received;
+            total.bytes_sent += second.bytes_sent;
+        }
+        for (error, count) in &self.transport_errors {
+            *summary.transport_errors.entry(*error).or_insert(0) += count;
+        }
 
         let latency = std::mem::replace(&mut self.latency, Histogram::<u64>::new(1).unwrap());
         summary.latency += latency;
+        summary.tally.add(&self.tally);
+        for (class, latency) in &self.class_latency {
+            *summary
+                .class_latency
+                .entry(*class)
+                .or_insert_with(latency_histogram) += latency;
+        }
+
+        for (phase, latency) in &self.phase_latency {
+            *summary
+                .phase_latency
+                .entry(*phase)
+                .or_insert_with(latency_histogram) += latency;
+        }
+
+        summary.first_attempt_latency += &self.first_attempt_latency;
+        summary.end_to_end_latency += &self.end_to_end_latency;
+        summary.retries += self.retries;
+        summary.retried_requests += self.retried_requests;
+        summary.retries_exhausted += self.retries_exhausted;
 
         summary.request_times.append(&mut self.request_times);
+        summary.sessions += self.sessions;
+
+        let setup_latency =
+            std::mem::replace(&mut self.setup_latency, Histogram::<u64>::new(1).unwrap());
+        summary.setup_latency += setup_latency;
+        summary.setup_errors += self.setup_errors;
+
+        summary.assertion_failures += self.assertion_failures;
+        for (assertion, count) in &self.failed_assertions {
+            *summary
+                .failed_assertions
+                .entry(assertion.clone())
+                .or_insert(0) += count;
+        }
+    }
+}
+
+/// The requests, errors and latency the thresholds are checked against. They're counted the same way while the run
+/// is watched (to stop it early) and once it's over, so both come to the same verdict
+#[derive(Clone)]
+pub(crate) struct Tally {
+    pub requests: u64,
+    pub errors: u64,
+    // Requests that got a response, and those that timed out (taking about as long as the timeout) so a backend that
+    // hangs can't pass a latency threshold. Other failures are usually quick, so would flatter the latency
+    pub latency: Histogram<u64>,
+}
+
+impl Tally {
+    pub(crate) fn new() -> Tally {
+        Tally {
+            requests: 0,
+            errors: 0,
+            latency: latency_histogram(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, class: OutcomeClass, latency: u64) {
+        self.requests += 1;
+        if class.is_error() {
+            self.errors += 1;
+        }
+        if class != OutcomeClass::Transport {
+            self.latency += latency;
+        }
+    }
+
+    /// The share of requests that were errors
+    pub(crate) fn error_rate(&self) -> f64 {
+        self.errors as f64 / self.requests.max(1) as f64
+    }
+
+    fn add(&mut self, other: &Tally) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.latency += &other.latency;
+    }
+}
+
+/// Requests completed and bytes transferred over some period
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Transfer {
+    pub requests: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+// We measure latency in microseconds, so configure the histogram to track 1 microsecond to 100 seconds. Two
+// significant figures keeps each worker's histograms small while still resolving sub-millisecond differences
+pub(crate) fn latency_histogram() -> Histogram<u64> {
+    Histogram::<u64>::new_with_bounds(1, 1_000_000 * 100, 2).unwrap()
+}
+
+/// The broad outcome of a request, used to break down latency. Redirects aren't followed, so are a class of their own
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum OutcomeClass {
+    Informational,
+    Success,
+    Redirect,
+    ClientError,
+    ServerError,
+    // A status code outside the ranges defined by the HTTP spec
+    Other,
+    // One of the configured timeouts fired
+    Timeout,
+    // No response was received (e.g. the connection was refused or reset)
+    Transport,
+}
+
+impl OutcomeClass {
+    /// Whether the request counts as an error, i.e. didn't get a 1xx, 2xx or 3xx response
+    pub(crate) fn is_error(&self) -> bool {
+        !matches!(
+            self,
+            OutcomeClass::Informational | OutcomeClass::Success | OutcomeClass::Redirect
+        )
+    }
+
+    fn from_status(status_code: u16) -> OutcomeClass {
+        match status_code {
+            100..=199 => OutcomeClass::Informational,
+            200..=299 => OutcomeClass::Success,
+            300..=399 => OutcomeClass::Redirect,
+            400..=499 => OutcomeClass::ClientError,
+            500..=599 => OutcomeClass::ServerError,
+            _ => OutcomeClass::Other,
+        }
+    }
+}
+
+impl fmt::Display for OutcomeClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OutcomeClass::Informational => "1xx",
+            OutcomeClass::Success => "2xx",
+            OutcomeClass::Redirect => "3xx",
+            OutcomeClass::ClientError => "4xx",
+            OutcomeClass::ServerError => "5xx",
+            OutcomeClass::Other => "other status",
+            OutcomeClass::Timeout => "timeout",
+            OutcomeClass::Transport => "transport error",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Describes how each request is built
+#[derive(Clone)]
+pub(crate) struct RequestOptions {
+    pub http_method: HttpMethod,
+    pub headers: Vec<Header>,
+    pub auth: Option<Arc<Auth>>,
+    pub signer: Option<Arc<dyn Signer>>,
+    // Each worker keeps a cookie jar (modelling one browser session) if set
+    pub session: Option<SessionPolicy>,
+    // Requests each worker sends before the load begins
+    pub setup: Option<Arc<Vec<SetupStep>>>,
+    // Checks applied to every response
+    pub assertions: Arc<Vec<Assertion>>,
+    // Failed requests are retried if set
+    pub retry: Option<RetryPolicy>,
+    // Response bodies are read and thrown away rather than kept for the assertions if set
+    pub discard_body: bool,
+}
+
+/// State shared by the workers while the test runs
+pub(crate) struct RunState {
+    // Workers wait here until they've all completed any setup, so the load starts together
+    load_start: Barrier,
+    // Set once the load starts
+    pub load_started: OnceLock<Instant>,
+    pub timeline: Arc<Mutex<Timeline>>,
+    // Live counts for watching the test: attempts waiting on a response, the responses so far and requests that
+    // failed without one. Every response takes the lock, so the responses are only kept if something is watching
+    pub in_flight: AtomicU32,
+    pub responses: Option<Mutex<Responses>>,
+    pub failures: AtomicU64,
+}
+
+/// The responses received so far in the run
+pub(crate) struct Responses {
+    pub status: BTreeMap<u16, u64>,
+    pub tally: Tally,
+}
+
+impl RunState {
+    pub(crate) fn new(concurrency: u16, timeline: Timeline, watched: bool) -> RunState {
+        RunState {
+            // The workers and the thread that starts them
+            load_start: Barrier::new(concurrency as usize + 1),
+            load_started: OnceLock::new(),
+            timeline: Arc::new(Mutex::new(timeline)),
+            in_flight: AtomicU32::new(0),
+            responses: watched.then(|| {
+                Mutex::new(Responses {
+                    status: BTreeMap::new(),
+                    tally: Tally::new(),
+                })
+            }),
+            failures: AtomicU64::new(0),
+        }
     }
 }
 
 /// Starts workers that pull requests from the generator, runs them and tracks benchmark statistics
 pub(crate) fn run_test(
     agent: Agent,
-    http_method: HttpMethod,
-    header_map: Option<HashMap<String, String>>,
+    options: RequestOptions,
     concurrency: u16,
-    request_generator: RequestGenerator,
-    urls: &'static [String],
-    payloads: &'static [String],
+    request_generator: Arc<RequestGenerator>,
+    urls: &'static UrlSource,
+    payloads: &'static [Template],
+    state: Arc<RunState>,
 ) -> BenchResult {
-    let request_generator = Arc::new(request_generator);
     let results = Arc::new(Mutex::new(Vec::new()));
 
     info!("Starting test with {} workers", concurrency);
@@ -66,15 +358,16 @@ pub(crate) fn run_test(
     for worker_id in 0..concurrency {
         let request_generator = request_generator.clone();
         let results = results.clone();
-        let header_map = header_map.clone();
+        let options = options.clone();
         let agent = agent.clone();
+        let state = state.clone();
         let worker = thread::spawn(move || {
             let result = run_worker(
                 worker_id,
                 request_generator,
                 agent,
-                http_method,
-                header_map,
+                options,
+                state,
                 urls,
                 payloads,
             );
@@ -85,10 +378,23 @@ pub(crate) fn run_test(
     }
 
     // Wait for workers to complete
+    if options.setup.is_some() {
+        info!("Waiting for workers to complete setup");
+    }
+    state.load_start.wait();
+    let start = *state.load_started.get_or_init(Instant::now);
+    state.timeline.lock().unwrap().started = SystemTime::now();
+    let dashboard = request_generator
+        .dashboard
+        .then(|| Dashboard::start(state.clone(), request_generator.clone(), start));
     info!("Waiting for workers to complete");
     for worker in workers {
         worker.join().unwrap();
     }
+    let run_time = start.elapsed();
+    if let Some(dashboard) = dashboard {
+        dashboard.finish();
+    }
 
     // Combine all the individual test results
     let mut merged = BenchResult::new();
@@ -101,6 +407,8 @@ pub(crate) fn run_test(
     for result in results.iter_mut() {
         result.add_to(&mut merged);
     }
+    merged.run_time = run_time;
+    merged.timeline = state.timeline.lock().unwrap().clone();
 
     merged
 }
@@ -109,12 +417,37 @@ fn run_worker(
     worker_id: u16,
     request_generator: Arc<RequestGenerator>,
     agent: Agent,
-    http_method: HttpMethod,
-    header_map: Option<HashMap<String, String>>,
-    urls: &'static [String],
-    payloads: &'static [String],
+    options: RequestOptions,
+    state: Arc<RunState>,
+    urls: &'static UrlSource,
+    payloads: &'static [Template],
 ) -> BenchResult {
+    let http_method = options.http_method;
     let mut result = BenchResult::new();
+    let mut sequence = 0;
+    let mut session = options.session.map(Session::new);
+
+    // Run any setup steps, then wait for the other workers before starting the load
+    let variables = match &options.setup {
+        Some(steps) => setup::run(
+            steps,
+            worker_id,
+            &agent,
+            &options,
+            session.as_mut(),
+            &mut result,
+        )
+        .map_err(|e| {
+            result.setup_errors += 1;
+            error!("{} -> setup failed, stopping: {}", worker_id, e);
+        }),
+        None => Ok(HashMap::new()),
+    };
+    state.load_start.wait();
+    let mut timeline = IntervalRecorder::new(state.timeline.clone(), Instant::now());
+    let Ok(variables) = variables else {
+        return result;
+    };
 
     // Execute requests until we are done
     while let Some(hb_request) = request_generator.next() {
@@ -127,89 +460,614 @@ fn run_worker(
 
         // Initialise the request
         // When testing POST or PUT only one url is provided
-        let url = match http_method {
-            HttpMethod::Post | HttpMethod::Put => urls[0].as_str(),
-            _ => urls[hb_request.url_index].as_str(),
+        let requested_index = match http_method {
+            HttpMethod::Post | HttpMethod::Put => 0,
+            _ => hb_request.url_index,
         };
+        let Some((url_index, url)) = urls.fetch(requested_index) else {
+            error!("{} -> unable to read the next URL, stopping", worker_id);
+            break;
+        };
+        let (url, inline_headers) = urlsource::split_line(&url);
+        let inline_headers = headers::split_inline(inline_headers).collect::<Vec<_>>();
+        let context = RequestContext {
+            worker_id,
+            sequence,
+            variables: &variables,
+        };
+        sequence += 1;
 
-        // Execute the request
-        let start = Instant::now();
-        let ureq_response = match http_method {
-            HttpMethod::Get => {
-                // Prepare the request
-                let mut builder = agent.get(url);
-
-                // Add the headers
-                if let Some(ref hm) = header_map {
-                    for (header, value) in hm {
-                        builder = builder.header(header, value.as_str());
-                    }
+        // When testing POST or PUT the payload is taken from the list of payloads
+        let payload = match http_method {
+            HttpMethod::Post | HttpMethod::Put => {
+                Some(payloads[hb_request.url_index].render(&context))
+            }
+            _ => None,
+        };
+        let payload = payload.as_deref();
+
+        if let Some(session) = session.as_mut() {
+            session.begin_request();
+        }
+
+        // Execute the request, retrying if the policy says so. Only the last attempt counts toward the results
+        let request = RequestParts {
+            url,
+            inline_headers: &inline_headers,
+            context: &context,
+            payload,
+        };
+        let last = run_request(
+            &agent,
+            &options,
+            &request,
+            session.as_mut(),
+            &state,
+            &mut result,
+        );
+        record(last, url_index, &state, &mut timeline, &mut result);
+    }
+
+    timeline.flush();
+
+    if let Some(session) = session {
+        result.sessions = session.resets() + 1;
+    }
+
+    result
+}
+
+/// Everything about a request that stays the same across its attempts
+struct RequestParts<'a> {
+    url: &'a str,
+    inline_headers: &'a [(&'a str, &'a str)],
+    context: &'a RequestContext<'a>,
+    payload: Option<&'a str>,
+}
+
+// Sends the request, retrying if the policy says so, and returns the last attempt for recording. The retries (and
+// the latency of the first attempt and of all of them) are tracked on the way
+fn run_request<'a>(
+    agent: &Agent,
+    options: &'a RequestOptions,
+    request: &RequestParts,
+    mut session: Option<&mut Session>,
+    state: &RunState,
+    result: &mut BenchResult,
+) -> Attempt<'a> {
+    let RequestParts {
+        url,
+        inline_headers,
+        context,
+        payload,
+    } = *request;
+    let start = Instant::now();
+    let mut attempt = 0;
+    let last = loop {
+        // Build the headers for each attempt, so tokens are refreshed and signatures are current after a backoff.
+        // This fetches auth tokens and signs the request before we start timing it
+        let prepare_start = Instant::now();
+        let cookies = session.as_deref().and_then(|session| {
+            Url::parse(url)
+                .ok()
+                .and_then(|url| session.cookie_header(&url))
+        });
+        let request_headers =
+            match build_headers(options, inline_headers, cookies, context, url, payload) {
+                Ok(request_headers) => request_headers,
+                Err(e) => {
+                    // The request fails without being sent, which counts like any other failure. It's the last
+                    // attempt, so replaces any earlier one rather than being counted as well
+                    warn!("Unable to prepare request for {}: {}", url, e);
+                    break Attempt::unsent(prepare_start.elapsed());
                 }
+            };
+
+        let current = execute(
+            agent,
+            options,
+            url,
+            &request_headers,
+            payload,
+            session.as_deref_mut(),
+            state,
+        );
+
+        let Some(retry) = &options.retry else {
+            break current;
+        };
+        if attempt == 0 {
+            result.first_attempt_latency += current.duration;
+        }
+        if !retry.should_retry(&current.outcome) {
+            break current;
+        }
+        if attempt == retry.max_retries {
+            result.retries_exhausted += 1;
+            break current;
+        }
 
-                // Run it
-                builder.call()
+        if attempt == 0 {
+            result.retried_requests += 1;
+        }
+        result.retries += 1;
+        debug!(
+            "{} -> retrying {} after {:?}",
+            context.worker_id, url, current.outcome
+        );
+        thread::sleep(retry.backoff(attempt));
+        attempt += 1;
+    };
+    if options.retry.is_some() {
+        result.end_to_end_latency += start.elapsed().as_micros() as u64;
+    }
+
+    last
+}
+
+/// What happened on a single attempt at a request, kept until we know whether it's the last
+struct Attempt<'a> {
+    outcome: Outcome,
+    class: OutcomeClass,
+    duration: u64,
+    // None if no response was received
+    status: Option<u16>,
+    phase_timings: Option<(Connection, Vec<(Phase, Duration)>)>,
+    sent: u64,
+    received: u64,
+    failed_assertions: Vec<&'a Assertion>,
+}
+
+impl Attempt<'_> {
+    // An attempt that failed before the request could be sent, timed up to the failure
+    fn unsent(duration: Duration) -> Attempt<'static> {
+        Attempt {
+            outcome: Outcome::Transport(TransportError::Unsent),
+            class: OutcomeClass::Transport,
+            duration: duration.as_micros() as u64,
+            status: None,
+            phase_timings: None,
+            sent: 0,
+            received: 0,
+            failed_assertions: Vec::new(),
+        }
+    }
+}
+
+// Sends a single attempt at the request, timing it and checking the response
+fn execute<'a>(
+    agent: &Agent,
+    options: &'a RequestOptions,
+    url: &str,
+    headers: &[RequestHeader],
+    payload: Option<&str>,
+    session: Option<&mut Session>,
+    state: &RunState,
+) -> Attempt<'a> {
+    let sent = payload.map_or(0, |p| p.len() as u64);
+    state.in_flight.fetch_add(1, Ordering::Relaxed);
+    phases::begin();
+    let start = Instant::now();
+    let ureq_response = send(agent, options.http_method, url, headers, payload);
+
+    let attempt = match ureq_response {
+        Ok(mut response) => {
+            // Remember any cookies the server set
+            if let Some(session) = session
+                && let Ok(url) = Url::parse(url)
+            {
+                let set_cookies = response
+                    .headers()
+                    .get_all(SET_COOKIE)
+                    .iter()
+                    .filter_map(|v| v.to_str().ok());
+                session.store_cookies(&url, set_cookies);
             }
-            HttpMethod::Post | HttpMethod::Put => {
-                let mut builder = if http_method == HttpMethod::Post {
-                    agent.post(url)
-                } else {
-                    agent.put(url)
-                };
-
-                // Add the headers
-                if let Some(ref hm) = header_map {
-                    for (header, value) in hm {
-                        builder = builder.header(header, value.as_str());
-                    }
-                }
 
-                // TODO: allow user to override POST request content-type, setting it to json for now
-                let payload: &'static str = &payloads[hb_request.url_index];
-                builder
-                    .header("Content-Type", "application/json")
-                    .send(payload)
+            // Read the response and track errors
+            let body = read_body(response.body_mut(), options.discard_body);
+            let end = Instant::now();
+            let duration = (end - start).as_micros() as u64;
+
+            // Error responses are timed like any other, but still counted as errors
+            let status_code = response.status().as_u16();
+            if status_code >= 400 {
+                warn!("Hit error processing {}: {}", url, status_code);
             }
-        };
 
-        // Track response code statistics
-        let mut duration = 0;
-        let status_code = match ureq_response {
-            Ok(mut response) => {
-                // Read the response and track errors
-                if let Err(e) = response.body_mut().read_to_string() {
-                    result.response_errors += 1;
+            let mut received = 0;
+            let mut failed_assertions = Vec::new();
+            let outcome = match body {
+                Ok(body) => {
+                    received = body.len;
+                    let response =
+                        CheckedResponse::new(status_code, response.headers(), &body.bytes);
+                    failed_assertions = assertions::check(&options.assertions, &response);
+                    for assertion in &failed_assertions {
+                        debug!("Assertion failed for {}: {}", url, assertion);
+                    }
+                    Outcome::Status(status_code)
+                }
+                Err(e) => {
+                    let error = TransportError::classify(&e);
                     warn!("Error retrieving response for {}: {}", url, e);
+                    Outcome::Transport(error)
                 }
+            };
 
-                let end = Instant::now();
-                duration = end.duration_since(start).as_millis() as u64;
+            // A timeout while reading the body is a timeout like any other, whatever the status
+            let class = match outcome {
+                Outcome::Transport(error) if error.is_timeout() => OutcomeClass::Timeout,
+                _ => OutcomeClass::from_status(status_code),
+            };
+            Attempt {
+                outcome,
+                class,
+                duration,
+                status: Some(status_code),
+                // Break the time down by phase
+                phase_timings: Some(phases::finish(end)),
+                sent,
+                received,
+                failed_assertions,
+            }
+        }
+        Err(e) => {
+            // We didn't get a response, but still track how long it took to fail, and in which phase
+            let end = Instant::now();
+            let duration = (end - start).as_micros() as u64;
+            let error = TransportError::classify(&e);
+            warn!("Hit transport error processing {}: {}", url, e);
 
-                response.status().as_u16()
+            // Timeouts are reported separately from the other transport errors
+            let class = if error.is_timeout() {
+                OutcomeClass::Timeout
+            } else {
+                OutcomeClass::Transport
+            };
+            Attempt {
+                outcome: Outcome::Transport(error),
+                class,
+                duration,
+                status: None,
+                phase_timings: Some(phases::finish(end)),
+                sent,
+                received: 0,
+                failed_assertions: Vec::new(),
             }
-            Err(Error::StatusCode(code)) => {
+        }
+    };
+
+    state.in_flight.fetch_sub(1, Ordering::Relaxed);
+    attempt
+}
+
+// Records the last attempt at a request in the results
+fn record(
+    attempt: Attempt,
+    url_index: usize,
+    state: &RunState,
+    timeline: &mut IntervalRecorder,
+    result: &mut BenchResult,
+) {
+    let duration = attempt.duration;
+    match attempt.status {
+        Some(status_code) => {
+            if status_code >= 400 {
                 result.request_errors += 1;
-                warn!("Hit error processing {}: {}", url, code);
+            }
+            *result.status.entry(status_code).or_insert(0) += 1;
+        }
+        None => {
+            result.request_errors += 1;
+            state.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    result.tally.record(attempt.class, duration);
+    if let Some(responses) = &state.responses {
+        let mut responses = responses.lock().unwrap();
+        if let Some(status_code) = attempt.status {
+            *responses.status.entry(status_code).or_insert(0) += 1;
+        }
+        responses.tally.record(attempt.class, duration);
+    }
+    if let Outcome::Transport(error) = attempt.outcome {
+        // A response whose body we couldn't read
+        if attempt.status.is_some() {
+            result.response_errors += 1;
+        }
+        *result.transport_errors.entry(error).or_insert(0) += 1;
+    }
+
+    if !attempt.failed_assertions.is_empty() {
+        result.assertion_failures += 1;
+        for assertion in &attempt.failed_assertions {
+            *result
+                .failed_assertions
+                .entry(assertion.to_string())
+                .or_insert(0) += 1;
+        }
+    }
+
+    // Update the latency histograms. The overall latency only covers requests that got a response
+    let class = attempt.class;
+    *result
+        .class_latency
+        .entry(class)
+        .or_insert_with(latency_histogram) += duration;
+    if !matches!(class, OutcomeClass::Timeout | OutcomeClass::Transport) {
+        result.latency += duration;
+    }
+    if let Some((connection, timings)) = &attempt.phase_timings {
+        for (phase, time) in timings {
+            *result
+                .phase_latency
+                .entry((*connection, *phase))
+                .or_insert_with(latency_histogram) += time.as_micros() as u64;
+        }
+    }
+
+    // Track the per-request latency too, and over time
+    result.request_times.push((url_index, duration));
+    timeline.record(duration, class, attempt.phase_timings.as_ref());
+
+    // Track the bytes transferred, overall and in the second the request completed
+    result.bytes_received += attempt.received;
+    result.bytes_sent += attempt.sent;
+    let second = timeline.started().elapsed().as_secs() as usize;
+    if result.per_second.len() <= second {
+        result.per_second.resize(second + 1, Transfer::default());
+    }
+    let transfer = &mut result.per_second[second];
+    transfer.requests += 1;
+    transfer.bytes_received += attempt.received;
+    transfer.bytes_sent += attempt.sent;
+}
 
-                code
+/// A response body we've read. If discarding bodies, we only keep the length
+struct ReadBody {
+    bytes: Vec<u8>,
+    len: u64,
+}
+
+// Reads the whole body as bytes (no matter the content type or size), or just drains it if discarding
+fn read_body(body: &mut Body, discard: bool) -> Result<ReadBody, ureq::Error> {
+    if discard {
+        let len = io::copy(&mut body.as_reader(), &mut io::sink())?;
+        return Ok(ReadBody {
+            bytes: Vec::new(),
+            len,
+        });
+    }
+
+    let bytes = body.with_config().limit(u64::MAX).read_to_vec()?;
+    Ok(ReadBody {
+        len: bytes.len() as u64,
+        bytes,
+    })
+}
+
+// Builds the full list of headers for the request: the configured headers, any per-request headers from the URL file,
+// session cookies, the auth header and finally the signature headers (which cover everything before them)
+fn build_headers<'a>(
+    options: &'a RequestOptions,
+    inline_headers: &[(&'a str, &'a str)],
+    cookies: Option<String>,
+    context: &RequestContext,
+    url: &str,
+    payload: Option<&str>,
+) -> Result<Vec<RequestHeader<'a>>, Box<dyn Error>> {
+    let mut headers = options
+        .headers
+        .iter()
+        .map(|h| (Cow::Borrowed(h.name.as_str()), h.value.render(context)))
+        .collect::<Vec<_>>();
+    headers.extend(
+        inline_headers
+            .iter()
+            .map(|(name, value)| (Cow::Borrowed(*name), Cow::Borrowed(*value))),
+    );
+
+    // Session cookies share the Cookie header with any supplied by the user
+    if let Some(cookies) = cookies {
+        match headers
+            .iter_mut()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Cookie"))
+        {
+            Some((_, value)) => {
+                let value = value.to_mut();
+                value.push_str("; ");
+                value.push_str(&cookies);
             }
-            Err(Error::Http(transport)) => {
-                panic!("Hit transport layer error {}: {}", url, transport);
+            None => headers.push((Cow::Borrowed("Cookie"), Cow::Owned(cookies))),
+        }
+    }
+
+    // POST and PUT payloads default to JSON unless the user has supplied a content type
+    if payload.is_some()
+        && !headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+    {
+        headers.push((
+            Cow::Borrowed("Content-Type"),
+            Cow::Borrowed("application/json"),
+        ));
+    }
+
+    if let Some(auth) = &options.auth {
+        headers.push((
+            Cow::Borrowed("Authorization"),
+            Cow::Owned(auth.authorization()?.to_string()),
+        ));
+    }
+
+    if let Some(signer) = &options.signer {
+        let url = Url::parse(url)?;
+        let request = SigningRequest {
+            method: options.http_method.as_str(),
+            url: &url,
+            headers: &headers,
+            body: payload.unwrap_or_default().as_bytes(),
+        };
+        let signature = signer.sign(&request, SystemTime::now())?;
+        headers.extend(
+            signature
+                .into_iter()
+                .map(|(name, value)| (Cow::Owned(name), Cow::Owned(value))),
+        );
+    }
+
+    Ok(headers)
+}
+
+// Sends the request with the given headers (and payload for POST/PUT)
+fn send(
+    agent: &Agent,
+    http_method: HttpMethod,
+    url: &str,
+    headers: &[RequestHeader],
+    payload: Option<&str>,
+) -> Result<Response<Body>, ureq::Error> {
+    match http_method {
+        HttpMethod::Get => add_headers(agent.get(url), headers).call(),
+        HttpMethod::Post => add_headers(agent.post(url), headers).send(payload.unwrap_or_default()),
+        HttpMethod::Put => add_headers(agent.put(url), headers).send(payload.unwrap_or_default()),
+    }
+}
+
+fn add_headers<B>(mut builder: RequestBuilder<B>, headers: &[RequestHeader]) -> RequestBuilder<B> {
+    for (name, value) in headers {
+        builder = builder.header(name.as_ref(), value.as_ref());
+    }
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    // Serves one response per connection with each of the given status codes in turn
+    fn serve(statuses: Vec<u16>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    line.clear();
+                }
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
             }
-            Err(e) => {
-                panic!("Hit other error {}: {}", url, e);
+        });
+        url
+    }
+
+    // Signs the first request, then fails
+    struct FailingSigner(AtomicU32);
+
+    impl Signer for FailingSigner {
+        fn sign(
+            &self,
+            _request: &SigningRequest,
+            _now: SystemTime,
+        ) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+            match self.0.fetch_add(1, Ordering::Relaxed) {
+                0 => Ok(Vec::new()),
+                _ => Err("signing failed".into()),
             }
+        }
+    }
+
+    // Runs a single GET through the retry policy, recording the last attempt
+    fn run(url: &str, signer: Option<Arc<dyn Signer>>) -> (BenchResult, RunState) {
+        let agent: Agent = Agent::config_builder()
+            .http_status_as_error(false)
+            .build()
+            .into();
+        let options = RequestOptions {
+            http_method: HttpMethod::Get,
+            headers: Vec::new(),
+            auth: None,
+            signer,
+            session: None,
+            setup: None,
+            assertions: Arc::new(Vec::new()),
+            retry: Some(RetryPolicy {
+                max_retries: 2,
+                retry_on: retry::RetryCondition::parse_list("5xx").unwrap(),
+                backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+            }),
+            discard_body: false,
+        };
+        let state = RunState::new(1, Timeline::new(Duration::from_secs(1)), false);
+        let mut timeline = IntervalRecorder::new(state.timeline.clone(), Instant::now());
+        let variables = HashMap::new();
+        let context = RequestContext {
+            worker_id: 0,
+            sequence: 0,
+            variables: &variables,
+        };
+        let request = RequestParts {
+            url,
+            inline_headers: &[],
+            context: &context,
+            payload: None,
         };
 
-        // Update response codes
-        let count = result.status.entry(status_code).or_insert(0);
-        *count += 1;
+        let mut result = BenchResult::new();
+        let last = run_request(&agent, &options, &request, None, &state, &mut result);
+        record(last, 0, &state, &mut timeline, &mut result);
+        (result, state)
+    }
 
-        // Update the latency histogram
-        result.latency += duration;
+    // Verify only the last attempt at a request is recorded, with the earlier ones counted as retries
+    #[test]
+    fn last_attempt_recorded() {
+        let (result, _) = run(&serve(vec![503, 503, 200]), None);
+        assert_eq!(HashMap::from([(200, 1)]), result.status);
+        assert_eq!(0, result.request_errors);
+        assert_eq!(2, result.retries);
+        assert_eq!(1, result.retried_requests);
+        assert_eq!(0, result.retries_exhausted);
+        assert_eq!(1, result.request_times.len());
+        assert_eq!(1, result.first_attempt_latency.len());
 
-        // Track the per-request latency too
-        result.request_times.push((hb_request.url_index, duration));
+        let (result, _) = run(&serve(vec![500, 502, 503]), None);
+        assert_eq!(HashMap::from([(503, 1)]), result.status);
+        assert_eq!(1, result.request_errors);
+        assert_eq!(2, result.retries);
+        assert_eq!(1, result.retries_exhausted);
+        assert_eq!(1, result.class_latency[&OutcomeClass::ServerError].len());
     }
 
-    result
+    // Verify a request that can't be prepared for a retry counts as a single unsent failure
+    #[test]
+    fn unsent_retry_recorded() {
+        let signer = Arc::new(FailingSigner(AtomicU32::new(0)));
+        let (result, state) = run(&serve(vec![503]), Some(signer));
+        assert!(result.status.is_empty());
+        assert_eq!(1, result.request_errors);
+        assert_eq!(
+            HashMap::from([(TransportError::Unsent, 1)]),
+            result.transport_errors
+        );
+        assert_eq!(1, state.failures.load(Ordering::Relaxed));
+        assert_eq!(1, result.retries);
+        assert_eq!(1, result.class_latency[&OutcomeClass::Transport].len());
+        assert!(
+            !result
+                .class_latency
+                .contains_key(&OutcomeClass::ServerError)
+        );
+    }
 }
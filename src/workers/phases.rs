@@ -0,0 +1,327 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use ureq::Error;
+use ureq::config::Config;
+use ureq::http::Uri;
+use ureq::unversioned::resolver::{DefaultResolver, ResolvedSocketAddrs, Resolver};
+use ureq::unversioned::transport::{
+    Buffers, ConnectProxyConnector, ConnectionDetails, Connector, NextTimeout, RustlsConnector,
+    TcpConnector, Transport,
+};
+
+/// A phase of a request, each of which is timed separately
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Phase {
+    Dns,
+    Connect,
+    // The TLS handshake (which rustls runs as the request is first written)
+    Tls,
+    // From the request being sent to the first byte of the response
+    FirstByte,
+    // From the first byte of the response to the end of the body
+    Download,
+}
+
+/// Whether the request opened a new connection or reused one from the pool
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Connection {
+    New,
+    Reused,
+}
+
+impl Connection {
+    /// A short name for the kind of connection
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Connection::New => "new",
+            Connection::Reused => "reused",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Phase::Dns => "dns",
+            Phase::Connect => "connect",
+            Phase::Tls => "tls",
+            Phase::FirstByte => "first byte",
+            Phase::Download => "download",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Connection::New => "new connections",
+            Connection::Reused => "reused connections",
+        };
+        f.write_str(name)
+    }
+}
+
+// What we've seen of the current attempt so far. ureq runs each request on the calling thread, so the resolver and
+// transport hooks record into a thread local that the worker reads once the body has been read
+#[derive(Default)]
+struct Probe {
+    dns: Option<Duration>,
+    connect_start: Option<Instant>,
+    connect: Option<Duration>,
+    tls: Option<Duration>,
+    new_connection: bool,
+    request_sent: Option<Instant>,
+    first_byte: Option<Instant>,
+}
+
+thread_local! {
+    static PROBE: RefCell<Probe> = RefCell::default();
+}
+
+/// Starts timing the phases of a new attempt on this thread
+pub(crate) fn begin() {
+    PROBE.set(Probe::default());
+}
+
+/// Finishes timing the attempt, returning the kind of connection it used and the time spent in each phase
+pub(crate) fn finish(end: Instant) -> (Connection, Vec<(Phase, Duration)>) {
+    let probe = PROBE.take();
+    let connection = match probe.new_connection {
+        true => Connection::New,
+        false => Connection::Reused,
+    };
+
+    // A request that failed part way through spent the rest of its time in the phase it failed in
+    let connect = probe.connect.or_else(|| {
+        probe
+            .connect_start
+            .map(|start| end.saturating_duration_since(start))
+    });
+    let first_byte = probe.request_sent.map(|sent| {
+        probe
+            .first_byte
+            .unwrap_or(end)
+            .saturating_duration_since(sent)
+    });
+    let download = probe
+        .first_byte
+        .map(|first_byte| end.saturating_duration_since(first_byte));
+    let phases = [
+        (Phase::Dns, probe.dns),
+        (Phase::Connect, connect),
+        (Phase::Tls, probe.tls),
+        (Phase::FirstByte, first_byte),
+        (Phase::Download, download),
+    ]
+    .into_iter()
+    .filter_map(|(phase, duration)| duration.map(|d| (phase, d)))
+    .collect();
+
+    (connection, phases)
+}
+
+/// The connector chain ureq uses by default (without SOCKS proxies or native-tls), with hooks to time the connect and
+/// TLS phases. A CONNECT proxy runs the whole chain again to reach the proxy, which restarts the clock, so the connect
+/// phase of a proxied request runs from connecting to the proxy until the tunnel is open
+pub(crate) fn connector() -> impl Connector {
+    ().chain(Mark::ConnectStart)
+        .chain(ConnectProxyConnector::default())
+        .chain(TcpConnector::default())
+        .chain(Mark::ConnectEnd)
+        .chain(RustlsConnector::default())
+        .chain(TimedConnector)
+}
+
+/// The default resolver, timing each lookup
+#[derive(Debug, Default)]
+pub(crate) struct TimedResolver(DefaultResolver);
+
+impl Resolver for TimedResolver {
+    fn resolve(
+        &self,
+        uri: &Uri,
+        config: &Config,
+        timeout: NextTimeout,
+    ) -> Result<ResolvedSocketAddrs, Error> {
+        let start = Instant::now();
+        let addrs = self.0.resolve(uri, config, timeout);
+        // Requests through a CONNECT proxy look up the proxy as well as the host
+        PROBE.with_borrow_mut(|probe| {
+            probe.dns = Some(probe.dns.unwrap_or_default() + start.elapsed())
+        });
+        addrs
+    }
+}
+
+// Notes the time as the connection passes through the chain, leaving the connection itself alone
+#[derive(Debug)]
+enum Mark {
+    ConnectStart,
+    ConnectEnd,
+}
+
+impl<In: Transport> Connector<In> for Mark {
+    type Out = In;
+
+    fn connect(
+        &self,
+        _details: &ConnectionDetails,
+        chained: Option<In>,
+    ) -> Result<Option<In>, Error> {
+        let now = Instant::now();
+        PROBE.with_borrow_mut(|probe| match self {
+            Mark::ConnectStart => {
+                probe.connect_start = Some(now);
+                probe.new_connection = true;
+            }
+            Mark::ConnectEnd => probe.connect = probe.connect_start.map(|start| now - start),
+        });
+        Ok(chained)
+    }
+}
+
+// Wraps new connections so we can time the TLS handshake and the wait for the first byte
+#[derive(Debug)]
+struct TimedConnector;
+
+impl<In: Transport> Connector<In> for TimedConnector {
+    type Out = TimedTransport<In>;
+
+    fn connect(
+        &self,
+        _details: &ConnectionDetails,
+        chained: Option<In>,
+    ) -> Result<Option<Self::Out>, Error> {
+        Ok(chained.map(|inner| TimedTransport {
+            handshake_pending: inner.is_tls(),
+            inner,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct TimedTransport<T> {
+    inner: T,
+    // rustls completes the handshake as the first data is written on the connection
+    handshake_pending: bool,
+}
+
+impl<T: Transport> Transport for TimedTransport<T> {
+    fn buffers(&mut self) -> &mut dyn Buffers {
+        self.inner.buffers()
+    }
+
+    fn transmit_output(&mut self, amount: usize, timeout: NextTimeout) -> Result<(), Error> {
+        let start = Instant::now();
+        self.inner.transmit_output(amount, timeout)?;
+        let now = Instant::now();
+
+        PROBE.with_borrow_mut(|probe| {
+            if self.handshake_pending {
+                probe.tls = Some(now - start);
+                self.handshake_pending = false;
+            }
+            if probe.first_byte.is_none() {
+                probe.request_sent = Some(now);
+            }
+        });
+        Ok(())
+    }
+
+    fn await_input(&mut self, timeout: NextTimeout) -> Result<bool, Error> {
+        let made_progress = self.inner.await_input(timeout)?;
+        if made_progress {
+            PROBE.with_borrow_mut(|probe| {
+                probe.first_byte.get_or_insert_with(Instant::now);
+            });
+        }
+        Ok(made_progress)
+    }
+
+    fn is_open(&mut self) -> bool {
+        self.inner.is_open()
+    }
+
+    fn is_tls(&self) -> bool {
+        self.inner.is_tls()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    // Verify a request that failed while connecting spends the rest of its time connecting
+    #[test]
+    fn failed_connecting() {
+        let start = Instant::now();
+        PROBE.set(Probe {
+            dns: Some(5 * MS),
+            connect_start: Some(start + 5 * MS),
+            new_connection: true,
+            ..Probe::default()
+        });
+
+        assert_eq!(
+            (
+                Connection::New,
+                vec![(Phase::Dns, 5 * MS), (Phase::Connect, 95 * MS)]
+            ),
+            finish(start + 100 * MS)
+        );
+    }
+
+    // Verify a reused connection has no connect or TLS phase
+    #[test]
+    fn reused_connection() {
+        let start = Instant::now();
+        PROBE.set(Probe {
+            request_sent: Some(start + MS),
+            first_byte: Some(start + 21 * MS),
+            ..Probe::default()
+        });
+
+        assert_eq!(
+            (
+                Connection::Reused,
+                vec![(Phase::FirstByte, 20 * MS), (Phase::Download, 9 * MS)]
+            ),
+            finish(start + 30 * MS)
+        );
+    }
+
+    // Verify a request without a response spends the rest of its time waiting for the first byte
+    #[test]
+    fn no_first_byte() {
+        let start = Instant::now();
+        PROBE.set(Probe {
+            dns: Some(MS),
+            connect_start: Some(start + MS),
+            connect: Some(2 * MS),
+            tls: Some(10 * MS),
+            new_connection: true,
+            request_sent: Some(start + 13 * MS),
+            first_byte: None,
+        });
+
+        assert_eq!(
+            (
+                Connection::New,
+                vec![
+                    (Phase::Dns, MS),
+                    (Phase::Connect, 2 * MS),
+                    (Phase::Tls, 10 * MS),
+                    (Phase::FirstByte, 987 * MS),
+                ]
+            ),
+            finish(start + 1000 * MS)
+        );
+        // The probe is reset for the next attempt
+        assert_eq!((Connection::Reused, Vec::new()), finish(start));
+    }
+}
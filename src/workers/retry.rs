@@ -0,0 +1,120 @@
+use std::error::Error;
+use std::time::Duration;
+
+use crate::workers::assertions;
+use crate::workers::transport::TransportError;
+
+// The transport error categories that can be retried, as named on the command line
+const TRANSPORT_KINDS: [&str; 7] = [
+    "dns",
+    "connect",
+    "reset",
+    "tls",
+    "timeout",
+    "truncated",
+    "protocol",
+];
+
+/// How failed requests are retried, mimicking the behaviour of real clients
+#[derive(Clone, Debug)]
+pub(crate) struct RetryPolicy {
+    pub max_retries: u32,
+    pub retry_on: Vec<RetryCondition>,
+    // The delay before the first retry, which doubles for each retry after that (up to the max)
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+/// An outcome that is worth retrying
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum RetryCondition {
+    // A range of status codes e.g. 500-599 for 5xx
+    Status(u16, u16),
+    // A category of transport error e.g. connect
+    Transport(&'static str),
+}
+
+/// What happened on an attempt, as far as deciding whether to retry goes
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Outcome {
+    Status(u16),
+    Transport(TransportError),
+}
+
+impl RetryCondition {
+    /// Parses a comma separated list of status codes, classes and transport error categories e.g. 503,5xx,connect
+    pub(crate) fn parse_list(conditions: &str) -> Result<Vec<RetryCondition>, Box<dyn Error>> {
+        conditions
+            .split(',')
+            .map(str::trim)
+            .map(|condition| {
+                if let Some(kind) = TRANSPORT_KINDS.iter().find(|kind| **kind == condition) {
+                    return Ok(RetryCondition::Transport(kind));
+                }
+                let (from, to) = assertions::parse_status(condition).map_err(|_| {
+                    format!(
+                        "Invalid retry condition '{}': expected a status code, class (e.g. 5xx) or one of {}",
+                        condition,
+                        TRANSPORT_KINDS.join(", ")
+                    )
+                })?;
+                Ok(RetryCondition::Status(from, to))
+            })
+            .collect()
+    }
+
+    fn matches(&self, outcome: &Outcome) -> bool {
+        match (self, outcome) {
+            (RetryCondition::Status(from, to), Outcome::Status(code)) => {
+                (*from..=*to).contains(code)
+            }
+            (RetryCondition::Transport(kind), Outcome::Transport(error)) => error.kind() == *kind,
+            _ => false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub(crate) fn should_retry(&self, outcome: &Outcome) -> bool {
+        self.retry_on.iter().any(|c| c.matches(outcome))
+    }
+
+    /// Returns how long to wait before the retry following this attempt (counting from 0)
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        // Exponential backoff with jitter, so retries from different workers don't all land together
+        let delay = self
+            .backoff
+            .saturating_mul(1 << attempt.min(31))
+            .min(self.max_backoff);
+        delay.mul_f64(rand::random_range(0.5..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify only matching outcomes are retried, with a growing backoff up to the limit
+    #[test]
+    fn retries_with_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            retry_on: RetryCondition::parse_list("5xx,429,reset").unwrap(),
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+        };
+
+        assert!(policy.should_retry(&Outcome::Status(503)));
+        assert!(policy.should_retry(&Outcome::Status(429)));
+        assert!(policy.should_retry(&Outcome::Transport(TransportError::ConnectionReset)));
+        assert!(!policy.should_retry(&Outcome::Status(404)));
+        assert!(!policy.should_retry(&Outcome::Transport(TransportError::Dns)));
+
+        assert!((50..=100).contains(&policy.backoff(0).as_millis()));
+        assert!((100..=200).contains(&policy.backoff(1).as_millis()));
+        assert!((150..=300).contains(&policy.backoff(2).as_millis()));
+        assert!((150..=300).contains(&policy.backoff(20).as_millis()));
+
+        assert!(RetryCondition::parse_list("5xx,nope").is_err());
+    }
+}
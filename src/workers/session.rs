@@ -0,0 +1,114 @@
+use std::time::{Duration, Instant};
+
+use cookie_store::CookieStore;
+use url::Url;
+
+/// When a virtual user's session is thrown away and a new one started
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SessionPolicy {
+    pub reset_requests: Option<u64>,
+    pub reset_after: Option<Duration>,
+}
+
+/// A virtual user's browser session: cookies set by responses are replayed on the worker's later requests until the
+/// session is reset
+pub(crate) struct Session {
+    policy: SessionPolicy,
+    jar: CookieStore,
+    requests: u64,
+    started: Instant,
+    resets: u64,
+}
+
+impl Session {
+    pub(crate) fn new(policy: SessionPolicy) -> Session {
+        Session {
+            policy,
+            jar: CookieStore::new(),
+            requests: 0,
+            started: Instant::now(),
+            resets: 0,
+        }
+    }
+
+    /// Called before each request, starting a new session if the current one has run its course
+    pub(crate) fn begin_request(&mut self) {
+        let expired_requests = self
+            .policy
+            .reset_requests
+            .is_some_and(|limit| self.requests >= limit);
+        let expired_time = self
+            .policy
+            .reset_after
+            .is_some_and(|limit| self.started.elapsed() >= limit);
+        if expired_requests || expired_time {
+            self.jar.clear();
+            self.requests = 0;
+            self.started = Instant::now();
+            self.resets += 1;
+        }
+        self.requests += 1;
+    }
+
+    /// The Cookie header value for a request to the URL, if we hold any matching cookies
+    pub(crate) fn cookie_header(&self, url: &Url) -> Option<String> {
+        let cookies = self
+            .jar
+            .get_request_values(url)
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>();
+        (!cookies.is_empty()).then(|| cookies.join("; "))
+    }
+
+    /// Stores the cookies from the Set-Cookie headers of a response
+    pub(crate) fn store_cookies<'a, I>(&mut self, url: &Url, set_cookies: I)
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        for set_cookie in set_cookies {
+            if let Err(e) = self.jar.parse(set_cookie, url) {
+                debug!("Ignoring cookie '{}' from {}: {}", set_cookie, url, e);
+            }
+        }
+    }
+
+    /// The number of times this session has been reset
+    pub(crate) fn resets(&self) -> u64 {
+        self.resets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify cookies are replayed to matching URLs and dropped when the session resets
+    #[test]
+    fn session_cookies() {
+        let mut session = Session::new(SessionPolicy {
+            reset_requests: Some(2),
+            reset_after: None,
+        });
+        let url = Url::parse("http://example.com/app/page").unwrap();
+        let other = Url::parse("http://other.com/").unwrap();
+
+        session.begin_request();
+        assert_eq!(None, session.cookie_header(&url));
+        session.store_cookies(&url, ["sid=abc; Path=/", "theme=dark; Path=/app"]);
+
+        session.begin_request();
+        let mut cookies = session
+            .cookie_header(&url)
+            .unwrap()
+            .split("; ")
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        cookies.sort();
+        assert_eq!(vec!["sid=abc", "theme=dark"], cookies);
+        assert_eq!(None, session.cookie_header(&other));
+
+        session.begin_request();
+        assert_eq!(None, session.cookie_header(&url));
+        assert_eq!(1, session.resets());
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::str::FromStr;
+use std::time::Instant;
+
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use ureq::Agent;
+use ureq::http::header::SET_COOKIE;
+use url::Url;
+
+use crate::config::HttpMethod;
+use crate::headers;
+use crate::headers::Header;
+use crate::template::{RequestContext, Template};
+use crate::urlsource;
+use crate::workers::session::Session;
+use crate::workers::{BenchResult, RequestOptions, build_headers, read_body, send};
+
+/// A request each worker sends once before the load begins (e.g. to log in), capturing values from the response into
+/// variables for use in later requests
+#[derive(Debug)]
+pub(crate) struct SetupStep {
+    method: HttpMethod,
+    url: Template,
+    // Relative URLs are prefixed once rendered, as joining would escape the braces of their placeholders
+    url_prefix: Option<Url>,
+    headers: Vec<Header>,
+    body: Option<Template>,
+    captures: Vec<(String, Capture)>,
+}
+
+/// Where a variable is captured from in the setup response
+#[derive(Debug)]
+enum Capture {
+    // A JSON pointer into the body e.g. /data/token
+    Json(String),
+    // A response header
+    Header(String),
+    // A cookie set by the response
+    Cookie(String),
+    // The first capture group of a regex matched against the body
+    Regex(Regex),
+}
+
+// The setup file is a JSON array of these
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StepDefinition {
+    #[serde(default = "default_method")]
+    method: String,
+    url: String,
+    #[serde(default)]
+    headers: Vec<String>,
+    body: Option<String>,
+    #[serde(default)]
+    capture: BTreeMap<String, String>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+/// Loads the setup steps from a JSON file, returning them along with the names of all the variables they capture
+pub(crate) fn load(
+    path: &str,
+    url_prefix: Option<&Url>,
+) -> Result<(Vec<SetupStep>, HashSet<String>), Box<dyn Error>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read setup steps from {}: {}", path, e))?;
+    let definitions: Vec<StepDefinition> = serde_json::from_str(&contents)
+        .map_err(|e| format!("Invalid setup steps in {}: {}", path, e))?;
+
+    // Each step can use the variables captured by the steps before it
+    let mut variables = HashSet::new();
+    let mut steps = Vec::new();
+    for definition in definitions {
+        let method = HttpMethod::from_str(&definition.method)
+            .map_err(|_| format!("Unsupported setup method {}", definition.method))?;
+
+        let headers = definition
+            .headers
+            .iter()
+            .map(|h| headers::parse(h, &variables))
+            .collect::<Result<Vec<_>, _>>()?;
+        let body = definition
+            .body
+            .map(|body| Template::parse(&body, &variables));
+
+        let captures = definition
+            .capture
+            .into_iter()
+            .map(|(name, source)| Ok((name, Capture::parse(&source)?)))
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+        steps.push(SetupStep {
+            method,
+            url: Template::parse(&definition.url, &variables),
+            url_prefix: url_prefix.cloned(),
+            headers,
+            body,
+            captures,
+        });
+        variables.extend(
+            steps
+                .last()
+                .unwrap()
+                .captures
+                .iter()
+                .map(|(n, _)| n.clone()),
+        );
+    }
+
+    info!("Loaded {} setup steps from {}", steps.len(), path);
+    Ok((steps, variables))
+}
+
+impl SetupStep {
+    /// Whether any of the step's captures read the response body
+    pub(crate) fn needs_body(&self) -> bool {
+        self.captures
+            .iter()
+            .any(|(_, capture)| matches!(capture, Capture::Json(_) | Capture::Regex(_)))
+    }
+
+    // The URL for this worker's request, with any placeholders substituted and the prefix applied
+    fn url(&self, context: &RequestContext) -> Result<String, Box<dyn Error>> {
+        let mut url = self.url.render(context).into_owned();
+        if let Some(base) = &self.url_prefix {
+            urlsource::apply_prefix(base, &mut url)?;
+        }
+        Ok(url)
+    }
+}
+
+impl Capture {
+    fn parse(source: &str) -> Result<Capture, Box<dyn Error>> {
+        match source.split_once(':') {
+            Some(("json", pointer)) => Ok(Capture::Json(pointer.to_string())),
+            Some(("header", name)) => Ok(Capture::Header(name.to_string())),
+            Some(("cookie", name)) => Ok(Capture::Cookie(name.to_string())),
+            Some(("regex", pattern)) => Ok(Capture::Regex(Regex::new(pattern)?)),
+            _ => Err(format!(
+                "Invalid capture '{}': expected json:/pointer, header:name, cookie:name or regex:pattern",
+                source
+            )
+            .into()),
+        }
+    }
+
+    // Extracts the value from the response
+    fn extract(&self, headers: &ureq::http::HeaderMap, body: &str) -> Option<String> {
+        match self {
+            Capture::Json(pointer) => {
+                let json: Value = serde_json::from_str(body).ok()?;
+                match json.pointer(pointer)? {
+                    Value::String(s) => Some(s.clone()),
+                    Value::Null => None,
+                    value => Some(value.to_string()),
+                }
+            }
+            Capture::Header(name) => headers
+                .get(name.as_str())
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            Capture::Cookie(name) => headers
+                .get_all(SET_COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .filter_map(|v| v.split(';').next()?.split_once('='))
+                .find(|(n, _)| n.trim() == name)
+                .map(|(_, value)| value.trim().to_string()),
+            Capture::Regex(regex) => regex
+                .captures(body)
+                .and_then(|c| c.get(1).or_else(|| c.get(0)))
+                .map(|m| m.as_str().to_string()),
+        }
+    }
+}
+
+/// Runs the setup steps for a worker, returning the captured variables. Latency is tracked separately from the test
+pub(crate) fn run(
+    steps: &[SetupStep],
+    worker_id: u16,
+    agent: &Agent,
+    options: &RequestOptions,
+    mut session: Option<&mut Session>,
+    result: &mut BenchResult,
+) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let mut variables = HashMap::new();
+    for (sequence, step) in steps.iter().enumerate() {
+        // Only send the configured headers once we've captured the variables they use
+        let options = RequestOptions {
+            headers: options
+                .headers
+                .iter()
+                .filter(|h| h.value.is_resolved(&variables))
+                .cloned()
+                .collect(),
+            ..options.clone()
+        };
+
+        let context = RequestContext {
+            worker_id,
+            sequence: sequence as u64,
+            variables: &variables,
+        };
+        let url = step.url(&context)?;
+        let body = step.body.as_ref().map(|b| b.render(&context).into_owned());
+        let step_headers = step
+            .headers
+            .iter()
+            .map(|h| (h.name.clone(), h.value.render(&context).into_owned()))
+            .collect::<Vec<_>>();
+        let step_headers = step_headers
+            .iter()
+            .map(|(n, v)| (n.as_str(), v.as_str()))
+            .collect::<Vec<_>>();
+
+        let parsed_url = Url::parse(&url)?;
+        let cookies = session
+            .as_deref()
+            .and_then(|session| session.cookie_header(&parsed_url));
+        let request_headers = build_headers(
+            &options,
+            &step_headers,
+            cookies,
+            &context,
+            &url,
+            body.as_deref(),
+        )?;
+
+        // Send the request, timing it separately from the main test
+        let start = Instant::now();
+        let mut response = send(agent, step.method, &url, &request_headers, body.as_deref())
+            .map_err(|e| {
+                format!(
+                    "Setup request {} {} failed: {}",
+                    step.method.as_str(),
+                    url,
+                    e
+                )
+            })?;
+        let response_body = read_body(response.body_mut(), options.discard_body)?;
+        let response_body = String::from_utf8_lossy(&response_body.bytes);
+        result.setup_latency += start.elapsed().as_micros() as u64;
+
+        if response.status().as_u16() >= 400 {
+            return Err(format!(
+                "Setup request {} {} failed: {}",
+                step.method.as_str(),
+                url,
+                response.status()
+            )
+            .into());
+        }
+
+        if let Some(session) = session.as_deref_mut() {
+            let set_cookies = response
+                .headers()
+                .get_all(SET_COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok());
+            session.store_cookies(&parsed_url, set_cookies);
+        }
+
+        for (name, capture) in &step.captures {
+            let value = capture
+                .extract(response.headers(), &response_body)
+                .ok_or_else(|| {
+                    format!("Unable to capture {} ({:?}) from {}", name, capture, url)
+                })?;
+            debug!("{} -> captured {}={}", worker_id, name, value);
+            variables.insert(name.clone(), value);
+        }
+    }
+
+    Ok(variables)
+}
+
+#[cfg(test)]
+mod tests {
+    use ureq::http::HeaderMap;
+    use ureq::http::header::{HeaderValue, LOCATION};
+
+    use super::*;
+
+    // Verify each kind of capture extracts the value from the response
+    #[test]
+    fn captures() {
+        let mut headers = HeaderMap::new();
+        headers.insert(LOCATION, HeaderValue::from_static("/home"));
+        headers.append(SET_COOKIE, HeaderValue::from_static("a=1; Path=/"));
+        headers.append(SET_COOKIE, HeaderValue::from_static("sid=xyz; HttpOnly"));
+        let body = r#"{"token":"abc","user":{"id":7},"csrf":"<input value=\"q1\">"}"#;
+
+        let extract = |source: &str| Capture::parse(source).unwrap().extract(&headers, body);
+        assert_eq!(Some("abc".to_string()), extract("json:/token"));
+        assert_eq!(Some("7".to_string()), extract("json:/user/id"));
+        assert_eq!(None, extract("json:/missing"));
+        assert_eq!(Some("/home".to_string()), extract("header:location"));
+        assert_eq!(Some("xyz".to_string()), extract("cookie:sid"));
+        assert_eq!(Some("q1".to_string()), extract(r#"regex:value=\\"(\w+)"#));
+        assert!(Capture::parse("body:x").is_err());
+    }
+
+    // Verify placeholders in relative URLs are substituted before the prefix is applied
+    #[test]
+    fn prefixed_url() {
+        let step = SetupStep {
+            method: HttpMethod::Get,
+            url: Template::parse("/users/{{id}}", &HashSet::from(["id".to_string()])),
+            url_prefix: Some(Url::parse("http://localhost:8080/").unwrap()),
+            headers: Vec::new(),
+            body: None,
+            captures: Vec::new(),
+        };
+        let variables = HashMap::from([("id".to_string(), "7".to_string())]);
+        let context = RequestContext {
+            worker_id: 0,
+            sequence: 0,
+            variables: &variables,
+        };
+        assert_eq!("http://localhost:8080/users/7", step.url(&context).unwrap());
+    }
+}
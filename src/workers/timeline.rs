@@ -0,0 +1,359 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use hdrhistogram::Histogram;
+use hdrhistogram::serialization::V2DeflateSerializer;
+use hdrhistogram::serialization::interval_log::{IntervalLogWriterBuilder, Tag};
+use serde::{Deserialize, Serialize};
+
+use crate::workers::OutcomeClass;
+use crate::workers::phases::{Connection, Phase};
+
+/// Statistics for each interval of the test, so we can see how things changed over the run
+#[derive(Clone)]
+pub(crate) struct Timeline {
+    pub interval: Duration,
+    // When the load started
+    pub started: SystemTime,
+    // Whether each interval also breaks the latency down by class of response and phase (for the interval log)
+    tagged: bool,
+    pub intervals: Vec<Interval>,
+}
+
+/// Requests completed in one interval
+#[derive(Clone)]
+pub(crate) struct Interval {
+    pub requests: u64,
+    pub errors: u64,
+    pub latency: Histogram<u64>,
+    pub tagged: BTreeMap<IntervalTag, Histogram<u64>>,
+}
+
+/// A breakdown of the latency in each interval, named with a tag in the interval log
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum IntervalTag {
+    Class(OutcomeClass),
+    Phase(Connection, Phase),
+}
+
+impl fmt::Display for IntervalTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Tags can't contain spaces
+        let name = match self {
+            IntervalTag::Class(class) => class.to_string(),
+            IntervalTag::Phase(connection, phase) => format!("{}-{}", connection.name(), phase),
+        };
+        f.write_str(&name.replace(' ', "-"))
+    }
+}
+
+// Each interval usually covers a narrow range of latencies, so let the histograms grow as needed
+fn interval_histogram() -> Histogram<u64> {
+    Histogram::<u64>::new(2).unwrap()
+}
+
+impl Interval {
+    fn new() -> Interval {
+        Interval {
+            requests: 0,
+            errors: 0,
+            latency: interval_histogram(),
+            tagged: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, other: &Interval) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.latency += &other.latency;
+        for (tag, latency) in &other.tagged {
+            *self.tagged.entry(*tag).or_insert_with(interval_histogram) += latency;
+        }
+    }
+
+    fn record_tagged(&mut self, tag: IntervalTag, latency: u64) {
+        *self.tagged.entry(tag).or_insert_with(interval_histogram) += latency;
+    }
+}
+
+impl Timeline {
+    pub(crate) fn new(interval: Duration) -> Timeline {
+        Timeline {
+            interval,
+            started: SystemTime::now(),
+            tagged: false,
+            intervals: Vec::new(),
+        }
+    }
+
+    /// Also breaks the latency in each interval down by class of response and phase
+    pub(crate) fn tagged(mut self) -> Timeline {
+        self.tagged = true;
+        self
+    }
+
+    fn add(&mut self, index: usize, interval: &Interval) {
+        if self.intervals.len() <= index {
+            self.intervals.resize_with(index + 1, Interval::new);
+        }
+        self.intervals[index].add(interval);
+    }
+
+    /// Combines adjacent intervals so there are at most the given number, returning them with their start times
+    pub(crate) fn compact(&self, max_rows: usize) -> Vec<(Duration, Interval)> {
+        let per_row = self.intervals.len().div_ceil(max_rows.max(1)).max(1);
+        self.intervals
+            .chunks(per_row)
+            .enumerate()
+            .map(|(row, chunk)| {
+                let mut combined = Interval::new();
+                chunk.iter().for_each(|interval| combined.add(interval));
+                (self.interval * (row * per_row) as u32, combined)
+            })
+            .collect()
+    }
+
+    /// Each interval, as written to the time series file
+    pub(crate) fn rows(&self) -> Vec<TimelineRow> {
+        self.intervals
+            .iter()
+            .enumerate()
+            .map(|(index, interval)| TimelineRow::new(self.interval * index as u32, interval))
+            .collect()
+    }
+
+    /// Writes the timeline to a file, as JSON if the file name ends in .json and CSV otherwise
+    pub(crate) fn write(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let rows = self.rows();
+        let mut out = BufWriter::new(File::create(path)?);
+        if path.ends_with(".json") {
+            serde_json::to_writer_pretty(&mut out, &rows)?;
+        } else {
+            writeln!(out, "{}", TimelineRow::CSV_HEADER)?;
+            for row in rows {
+                writeln!(out, "{}", row.to_csv())?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Writes the interval histograms in the HdrHistogram interval log format (.hlog), with the breakdowns by class of
+    /// response and phase as tagged histograms
+    pub(crate) fn write_hlog(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let mut serializer = V2DeflateSerializer::new();
+        let mut log = IntervalLogWriterBuilder::new()
+            .add_comment(&format!("Logged by hb {}", env!("CARGO_PKG_VERSION")))
+            .add_comment("Latency values are in microseconds")
+            .with_start_time(self.started)
+            .with_base_time(self.started)
+            .begin_log_with(&mut out, &mut serializer)?;
+
+        for (index, interval) in self.intervals.iter().enumerate() {
+            let start = self.interval * index as u32;
+            log.write_histogram(&interval.latency, start, self.interval, None)?;
+            for (tag, latency) in &interval.tagged {
+                let tag = tag.to_string();
+                log.write_histogram(latency, start, self.interval, Tag::new(&tag))?;
+            }
+        }
+
+        drop(log);
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// One interval as written to the time series file (latency in microseconds)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TimelineRow {
+    pub start_secs: f64,
+    pub requests: u64,
+    pub errors: u64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+impl TimelineRow {
+    pub(crate) const CSV_HEADER: &'static str =
+        "start_secs,requests,errors,p50_us,p90_us,p99_us,max_us";
+
+    fn new(start: Duration, interval: &Interval) -> TimelineRow {
+        TimelineRow {
+            start_secs: start.as_secs_f64(),
+            requests: interval.requests,
+            errors: interval.errors,
+            p50_us: interval.latency.value_at_percentile(50.0),
+            p90_us: interval.latency.value_at_percentile(90.0),
+            p99_us: interval.latency.value_at_percentile(99.0),
+            max_us: interval.latency.max(),
+        }
+    }
+
+    pub(crate) fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{}",
+            self.start_secs,
+            self.requests,
+            self.errors,
+            self.p50_us,
+            self.p90_us,
+            self.p99_us,
+            self.max_us
+        )
+    }
+}
+
+/// Records a worker's requests into the current interval, adding each interval to the shared timeline once it's over
+/// (so the timeline can be watched during the run without every request taking a lock)
+pub(crate) struct IntervalRecorder {
+    timeline: Arc<Mutex<Timeline>>,
+    interval: Duration,
+    tagged: bool,
+    started: Instant,
+    index: usize,
+    current: Interval,
+}
+
+impl IntervalRecorder {
+    pub(crate) fn new(timeline: Arc<Mutex<Timeline>>, started: Instant) -> IntervalRecorder {
+        let (interval, tagged) = {
+            let timeline = timeline.lock().unwrap();
+            (timeline.interval, timeline.tagged)
+        };
+        IntervalRecorder {
+            timeline,
+            interval,
+            tagged,
+            started,
+            index: 0,
+            current: Interval::new(),
+        }
+    }
+
+    /// When the worker started generating load
+    pub(crate) fn started(&self) -> Instant {
+        self.started
+    }
+
+    /// Records a request that completed now, with the time spent in each phase if it got a response
+    pub(crate) fn record(
+        &mut self,
+        latency: u64,
+        class: OutcomeClass,
+        phases: Option<&(Connection, Vec<(Phase, Duration)>)>,
+    ) {
+        let index = (self.started.elapsed().as_nanos() / self.interval.as_nanos()) as usize;
+        if index != self.index {
+            self.flush();
+            self.index = index;
+        }
+
+        self.current.requests += 1;
+        if class.is_error() {
+            self.current.errors += 1;
+        }
+        self.current.latency += latency;
+
+        if self.tagged {
+            self.current
+                .record_tagged(IntervalTag::Class(class), latency);
+            if let Some((connection, timings)) = phases {
+                for (phase, time) in timings {
+                    self.current.record_tagged(
+                        IntervalTag::Phase(*connection, *phase),
+                        time.as_micros() as u64,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Adds the current interval to the timeline
+    pub(crate) fn flush(&mut self) {
+        if self.current.requests > 0 {
+            let current = std::mem::replace(&mut self.current, Interval::new());
+            self.timeline.lock().unwrap().add(self.index, &current);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify intervals from different workers are combined, and compacted for display
+    #[test]
+    fn intervals_combined() {
+        let timeline = Arc::new(Mutex::new(Timeline::new(Duration::from_secs(1))));
+        let mut first = IntervalRecorder::new(timeline.clone(), Instant::now());
+        let mut second = IntervalRecorder::new(timeline.clone(), Instant::now());
+        let mut late = IntervalRecorder::new(
+            timeline.clone(),
+            Instant::now() - Duration::from_millis(2500),
+        );
+        first.record(100, OutcomeClass::Success, None);
+        second.record(300, OutcomeClass::ServerError, None);
+        late.record(500, OutcomeClass::Success, None);
+        first.flush();
+        second.flush();
+        late.flush();
+
+        let timeline = timeline.lock().unwrap();
+        assert_eq!(3, timeline.intervals.len());
+        assert_eq!(2, timeline.intervals[0].requests);
+        assert_eq!(1, timeline.intervals[0].errors);
+        assert_eq!(0, timeline.intervals[1].requests);
+
+        let compacted = timeline.compact(2);
+        assert_eq!(2, compacted.len());
+        assert_eq!(Duration::from_secs(2), compacted[1].0);
+        assert_eq!(1, compacted[1].1.requests);
+    }
+
+    // Verify the interval log has a histogram per interval, plus one per class and phase when tagged
+    #[test]
+    fn interval_log_tagged() {
+        use hdrhistogram::serialization::interval_log::{IntervalLogIterator, LogEntry};
+
+        let timeline = Arc::new(Mutex::new(Timeline::new(Duration::from_secs(1)).tagged()));
+        let mut recorder = IntervalRecorder::new(timeline.clone(), Instant::now());
+        let phases = (
+            Connection::New,
+            vec![(Phase::Connect, Duration::from_micros(50))],
+        );
+        recorder.record(100, OutcomeClass::Success, Some(&phases));
+        recorder.record(300, OutcomeClass::Transport, None);
+        recorder.flush();
+
+        let path = std::env::temp_dir().join(format!("hb-{}.hlog", std::process::id()));
+        let path = path.to_str().unwrap();
+        timeline.lock().unwrap().write_hlog(path).unwrap();
+        let log = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let tags = IntervalLogIterator::new(&log)
+            .filter_map(|entry| match entry.unwrap() {
+                LogEntry::Interval(h) => Some(h.tag().map(|t| t.as_str().to_string())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                None,
+                Some("2xx".to_string()),
+                Some("transport-error".to_string()),
+                Some("new-connect".to_string())
+            ],
+            tags
+        );
+    }
+}
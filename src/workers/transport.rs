@@ -0,0 +1,141 @@
+use std::fmt;
+use std::io;
+
+use ureq::Timeout;
+
+/// The broad cause of a request failing without a (complete) response
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) enum TransportError {
+    Dns,
+    ConnectFailed,
+    ConnectionReset,
+    Tls,
+    // Which timeout fired e.g. connect
+    Timeout(&'static str),
+    // The connection closed before the whole body was read
+    BodyTruncated,
+    // The server sent something that isn't valid HTTP
+    Protocol,
+    // The request was never sent, as building it failed (e.g. fetching an auth token or signing it)
+    Unsent,
+    Other,
+}
+
+impl TransportError {
+    pub(crate) fn is_timeout(&self) -> bool {
+        matches!(self, TransportError::Timeout(_))
+    }
+
+    /// A short name for the category, used to refer to it on the command line
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            TransportError::Dns => "dns",
+            TransportError::ConnectFailed => "connect",
+            TransportError::ConnectionReset => "reset",
+            TransportError::Tls => "tls",
+            TransportError::Timeout(_) => "timeout",
+            TransportError::BodyTruncated => "truncated",
+            TransportError::Protocol => "protocol",
+            TransportError::Unsent => "unsent",
+            TransportError::Other => "other",
+        }
+    }
+
+    /// Works out the category of a ureq error
+    pub(crate) fn classify(error: &ureq::Error) -> TransportError {
+        match error {
+            ureq::Error::HostNotFound => TransportError::Dns,
+            ureq::Error::ConnectionFailed => TransportError::ConnectFailed,
+            ureq::Error::Timeout(timeout) => TransportError::Timeout(match timeout {
+                Timeout::Resolve | Timeout::Connect => "connect",
+                Timeout::RecvBody => "body read",
+                Timeout::Global | Timeout::PerCall => "overall",
+                _ => "first byte",
+            }),
+            ureq::Error::Protocol(_) => TransportError::Protocol,
+            ureq::Error::Tls(_) | ureq::Error::Rustls(_) | ureq::Error::Pem(_) => {
+                TransportError::Tls
+            }
+            ureq::Error::Io(e) => TransportError::from_io(e),
+            _ => TransportError::Other,
+        }
+    }
+
+    fn from_io(error: &io::Error) -> TransportError {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::AddrNotAvailable => TransportError::ConnectFailed,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => TransportError::ConnectionReset,
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                TransportError::Timeout("socket")
+            }
+            io::ErrorKind::UnexpectedEof => TransportError::BodyTruncated,
+            // The standard library doesn't give failed lookups their own kind, so we have to go by the message
+            _ if error.to_string().contains("failed to lookup address") => TransportError::Dns,
+            _ => TransportError::Other,
+        }
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Dns => f.write_str("DNS lookup failed"),
+            TransportError::ConnectFailed => f.write_str("connect failed"),
+            TransportError::ConnectionReset => f.write_str("connection reset"),
+            TransportError::Tls => f.write_str("TLS error"),
+            TransportError::Timeout(timeout) => write!(f, "timeout ({})", timeout),
+            TransportError::BodyTruncated => f.write_str("body truncated"),
+            TransportError::Protocol => f.write_str("protocol error"),
+            TransportError::Unsent => f.write_str("request not sent"),
+            TransportError::Other => f.write_str("other"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify errors are put in the right category
+    #[test]
+    fn errors_classified() {
+        let io_error = |kind| ureq::Error::Io(io::Error::from(kind));
+        assert_eq!(
+            TransportError::Dns,
+            TransportError::classify(&ureq::Error::HostNotFound)
+        );
+        assert_eq!(
+            TransportError::ConnectFailed,
+            TransportError::classify(&io_error(io::ErrorKind::ConnectionRefused))
+        );
+        assert_eq!(
+            TransportError::ConnectionReset,
+            TransportError::classify(&io_error(io::ErrorKind::ConnectionReset))
+        );
+        assert_eq!(
+            TransportError::BodyTruncated,
+            TransportError::classify(&io_error(io::ErrorKind::UnexpectedEof))
+        );
+        assert_eq!(
+            TransportError::Tls,
+            TransportError::classify(&ureq::Error::Tls("bad certificate"))
+        );
+        assert_eq!(
+            TransportError::Timeout("connect"),
+            TransportError::classify(&ureq::Error::Timeout(Timeout::Connect))
+        );
+        assert_eq!(
+            TransportError::Timeout("body read"),
+            TransportError::classify(&ureq::Error::Timeout(Timeout::RecvBody))
+        );
+        assert_eq!(
+            TransportError::Other,
+            TransportError::classify(&ureq::Error::BadUri("x".to_string()))
+        );
+    }
+}